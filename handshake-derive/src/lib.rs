@@ -0,0 +1,282 @@
+//! `#[derive(Encode, Decode)]` for the wire types in `handshake::bitcoin`.
+//!
+//! Fields are serialized in declaration order using their own `Encode` and
+//! `Decode` impls (vectors are varint-prefixed by the `Vec` impl). A field can
+//! be tweaked with `#[wire(...)]`:
+//!
+//! - `big_endian`: integer in network byte order, like ports.
+//! - `varint`: integer as a CompactSize variable length integer.
+//! - `condition = "expr"`: only present on the wire when `expr` holds. The
+//!   expression sees earlier fields by reference, e.g. `"*version >= 70001"`,
+//!   as well as the encoding `context`, and the field decodes to its
+//!   `Default` when absent.
+//! - `skip`: never on the wire, decoded as `Default`.
+//! - `decode_with = "path"`: decoded by `path(buffer, context)` instead of
+//!   the field type's `Decode`, e.g. to apply a tighter limit. Encoding is
+//!   unchanged.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, Expr, Fields, GenericParam, LitStr, Type,
+};
+
+#[proc_macro_derive(Encode, attributes(wire))]
+pub fn derive_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_encode(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(Decode, attributes(wire))]
+pub fn derive_decode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_decode(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+enum Format {
+    Default,
+    BigEndian,
+    VarInt,
+    Skip,
+}
+
+struct Field {
+    /// Local binding used for the field in generated code.
+    binding: Ident,
+    /// Name in error paths, for named fields.
+    name: Option<String>,
+    /// `name` for named fields, `0`, `1`, ... for tuple structs.
+    member: TokenStream2,
+    ty: Type,
+    format: Format,
+    condition: Option<Expr>,
+    decode_with: Option<syn::Path>,
+}
+
+enum Shape {
+    Named,
+    Unnamed,
+    Unit,
+}
+
+fn parse_fields(input: &DeriveInput) -> syn::Result<(Shape, Vec<Field>)> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "Encode and Decode can only be derived for structs",
+        ));
+    };
+    let shape = match &data.fields {
+        Fields::Named(_) => Shape::Named,
+        Fields::Unnamed(_) => Shape::Unnamed,
+        Fields::Unit => Shape::Unit,
+    };
+
+    let mut fields = Vec::new();
+    for (index, field) in data.fields.iter().enumerate() {
+        let (binding, member) = match &field.ident {
+            Some(ident) => (ident.clone(), quote!(#ident)),
+            None => {
+                let index = syn::Index::from(index);
+                (format_ident!("field_{}", index), quote!(#index))
+            }
+        };
+        let mut format = Format::Default;
+        let mut condition = None;
+        let mut decode_with = None;
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("wire"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("big_endian") {
+                    format = Format::BigEndian;
+                } else if meta.path.is_ident("varint") {
+                    format = Format::VarInt;
+                } else if meta.path.is_ident("skip") {
+                    format = Format::Skip;
+                } else if meta.path.is_ident("condition") {
+                    let expr: LitStr = meta.value()?.parse()?;
+                    condition = Some(expr.parse::<Expr>()?);
+                } else if meta.path.is_ident("decode_with") {
+                    let path: LitStr = meta.value()?.parse()?;
+                    decode_with = Some(path.parse::<syn::Path>()?);
+                } else {
+                    return Err(meta.error("unknown wire attribute"));
+                }
+                Ok(())
+            })?;
+        }
+        fields.push(Field {
+            name: field.ident.as_ref().map(ToString::to_string),
+            binding,
+            member,
+            ty: field.ty.clone(),
+            format,
+            condition,
+            decode_with,
+        });
+    }
+    Ok((shape, fields))
+}
+
+fn add_bounds(input: &mut DeriveInput, bound: syn::Path) {
+    for param in input.generics.params.iter_mut() {
+        if let GenericParam::Type(param) = param {
+            param.bounds.push(parse_quote!(#bound));
+        }
+    }
+}
+
+/// Wraps a field's condition so it sees every earlier field by reference.
+/// Encoding destructures `self`, so the bindings are references already;
+/// decoding has to borrow the values decoded so far.
+fn condition(fields: &[Field], index: usize, borrow: bool) -> Option<TokenStream2> {
+    let expr = fields[index].condition.as_ref()?;
+    if !borrow {
+        return Some(quote!(#expr));
+    }
+    let earlier = fields[..index].iter().map(|field| &field.binding);
+    Some(quote! {
+        {
+            #( #[allow(unused_variables)] let #earlier = &#earlier; )*
+            #expr
+        }
+    })
+}
+
+fn expand_encode(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let (shape, fields) = parse_fields(&input)?;
+    add_bounds(&mut input, parse_quote!(::handshake::bitcoin::Encode));
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let bindings = fields.iter().map(|field| &field.binding);
+    let destructure = match shape {
+        Shape::Named => quote!(let Self { #(#bindings),* } = self;),
+        Shape::Unnamed => quote!(let Self( #(#bindings),* ) = self;),
+        Shape::Unit => quote!(),
+    };
+
+    let statements = fields.iter().enumerate().map(|(index, field)| {
+        let binding = &field.binding;
+        let encode = match field.format {
+            Format::Default => {
+                quote!(written += ::handshake::bitcoin::Encode::encode_with(#binding, buffer, context)?;)
+            }
+            Format::BigEndian => quote! {
+                written += ::handshake::bitcoin::Encode::encode_with(
+                    &::handshake::bitcoin::BigEndian(*#binding),
+                    buffer,
+                    context,
+                )?;
+            },
+            Format::VarInt => quote! {
+                written += ::handshake::bitcoin::Encode::encode_with(
+                    &::handshake::bitcoin::VariableInt(*#binding as u64),
+                    buffer,
+                    context,
+                )?;
+            },
+            Format::Skip => quote!(let _ = #binding;),
+        };
+        match condition(&fields, index, false) {
+            Some(condition) => quote!(if #condition { #encode }),
+            None => encode,
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::handshake::bitcoin::Encode for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn encode_with(
+                &self,
+                buffer: &mut impl ::handshake::__bytes::BufMut,
+                context: &::handshake::bitcoin::Context,
+            ) -> ::handshake::bitcoin::Result<usize> {
+                #destructure
+                #[allow(unused_mut)]
+                let mut written = 0;
+                #(#statements)*
+                Ok(written)
+            }
+        }
+    })
+}
+
+fn expand_decode(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let (shape, fields) = parse_fields(&input)?;
+    add_bounds(&mut input, parse_quote!(::handshake::bitcoin::Decode));
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let buffer = Ident::new("buffer", Span::call_site());
+
+    let statements = fields.iter().enumerate().map(|(index, field)| {
+        let binding = &field.binding;
+        let ty = &field.ty;
+        // Each of these evaluates to a `Result`.
+        let decode = match (&field.format, &field.decode_with) {
+            (Format::Skip, _) => quote!(::handshake::bitcoin::Result::Ok(
+                ::core::default::Default::default()
+            )),
+            (_, Some(path)) => quote!(#path(#buffer, context)),
+            (Format::Default, None) => {
+                quote!(<#ty as ::handshake::bitcoin::Decode>::decode_with(#buffer, context))
+            }
+            (Format::BigEndian, None) => quote! {
+                <::handshake::bitcoin::BigEndian<#ty> as ::handshake::bitcoin::Decode>::decode_with(
+                    #buffer,
+                    context,
+                )
+                .map(|value| value.0)
+            },
+            (Format::VarInt, None) => quote! {
+                <::handshake::bitcoin::VariableInt as ::handshake::bitcoin::Decode>::decode_with(
+                    #buffer,
+                    context,
+                )
+                .map(|value| value.0 as #ty)
+            },
+        };
+        // Tuple fields add nothing useful to the error path.
+        let decode = match &field.name {
+            Some(name) => quote!(#decode.map_err(|e| e.in_field(#name))?),
+            None => quote!(#decode?),
+        };
+        let decode = match condition(&fields, index, true) {
+            Some(condition) => quote! {
+                if #condition { #decode } else { ::core::default::Default::default() }
+            },
+            None => decode,
+        };
+        quote!(let #binding: #ty = #decode;)
+    });
+
+    let construct = match shape {
+        Shape::Named | Shape::Unnamed => {
+            let members = fields.iter().map(|field| &field.member);
+            let bindings = fields.iter().map(|field| &field.binding);
+            quote!(Self { #( #members: #bindings ),* })
+        }
+        Shape::Unit => quote!(Self),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::handshake::bitcoin::Decode for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn decode_with(
+                #buffer: &mut impl ::handshake::__bytes::Buf,
+                context: &::handshake::bitcoin::Context,
+            ) -> ::handshake::bitcoin::Result<Self> {
+                #(#statements)*
+                Ok(#construct)
+            }
+        }
+    })
+}
@@ -0,0 +1,34 @@
+//! Runs a mock node serving a fixture chain, for trying clients offline.
+//!
+//! Usage: mock-node [ADDR] [BLOCKS] [NETWORK]
+
+use handshake::bitcoin::mock::{fixture_chain, MockConfig, MockNode};
+use handshake::bitcoin::peer::PeerConfig;
+use handshake::bitcoin::Network;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:18444".to_string());
+    let blocks = match args.next() {
+        Some(blocks) => blocks.parse()?,
+        None => 100,
+    };
+    let network = match args.next() {
+        Some(network) => network.parse()?,
+        None => Network::Regtest,
+    };
+
+    let config = MockConfig {
+        peer: PeerConfig {
+            network,
+            ..MockConfig::default().peer
+        },
+        chain: fixture_chain(network, blocks),
+        ..MockConfig::default()
+    };
+    let addr = MockNode::new(config).listen(addr.parse()?).await?;
+    println!("Listening on {} with {} blocks", addr, blocks);
+    std::future::pending::<()>().await;
+    Ok(())
+}
@@ -0,0 +1,318 @@
+use crate::bitcoin::{Error, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Misbehavior points at which a peer gets banned, as in Bitcoin Core.
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// A range of addresses, like `203.0.113.0/24`. A single address is a subnet
+/// with a full-length prefix.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct Subnet {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// Masks `addr` down to its first `prefix` bits.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self> {
+        let addr = addr.to_canonical();
+        let network = match addr {
+            IpAddr::V4(ip) if prefix <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+            }
+            IpAddr::V6(ip) if prefix <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+            }
+            _ => {
+                return Err(Error::Parse(format!(
+                    "invalid prefix /{} for {}",
+                    prefix, addr
+                )))
+            }
+        };
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        Self::new(addr.to_canonical(), self.prefix).is_ok_and(|subnet| subnet == *self)
+    }
+}
+
+impl From<IpAddr> for Subnet {
+    fn from(addr: IpAddr) -> Self {
+        let addr = addr.to_canonical();
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        Self {
+            network: addr,
+            prefix,
+        }
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Parse(format!("invalid subnet {:?}", s));
+        match s.split_once('/') {
+            Some((addr, prefix)) => Self::new(
+                addr.parse().map_err(|_| invalid())?,
+                prefix.parse().map_err(|_| invalid())?,
+            ),
+            None => Ok(s.parse::<IpAddr>().map_err(|_| invalid())?.into()),
+        }
+    }
+}
+
+/// Banned subnets and when each ban expires. A list opened from a file is
+/// written back after every change.
+#[derive(Debug, Default)]
+pub struct BanList {
+    bans: BTreeMap<Subnet, SystemTime>,
+    path: Option<PathBuf>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the list from `path`, which doesn't need to exist yet. Each line
+    /// holds a subnet and the unix time its ban ends.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut bans = BTreeMap::new();
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+                    let (subnet, until) = line
+                        .split_once(' ')
+                        .ok_or_else(|| Error::Parse(format!("invalid ban entry {:?}", line)))?;
+                    let until = until
+                        .trim()
+                        .parse()
+                        .map_err(|_| Error::Parse(format!("invalid ban entry {:?}", line)))?;
+                    bans.insert(subnet.parse()?, UNIX_EPOCH + Duration::from_secs(until));
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Self {
+            bans,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    pub fn add(&mut self, subnet: Subnet, duration: Duration) -> Result<()> {
+        self.bans.insert(subnet, SystemTime::now() + duration);
+        self.save()
+    }
+
+    /// Returns whether `subnet` was banned.
+    pub fn remove(&mut self, subnet: &Subnet) -> Result<bool> {
+        let removed = self.bans.remove(subnet).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// Bans still in force, with their expiry.
+    pub fn list(&self) -> Vec<(Subnet, SystemTime)> {
+        let now = SystemTime::now();
+        self.bans
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(subnet, until)| (*subnet, *until))
+            .collect()
+    }
+
+    pub fn is_banned(&self, addr: IpAddr) -> bool {
+        let now = SystemTime::now();
+        self.bans
+            .iter()
+            .any(|(subnet, until)| *until > now && subnet.contains(addr))
+    }
+
+    /// Forgets expired bans.
+    pub fn sweep(&mut self) -> Result<()> {
+        let now = SystemTime::now();
+        let before = self.bans.len();
+        self.bans.retain(|_, until| *until > now);
+        if self.bans.len() != before {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut contents = String::new();
+        for (subnet, until) in &self.bans {
+            let until = until.duration_since(UNIX_EPOCH).unwrap_or_default();
+            contents.push_str(&format!("{} {}\n", subnet, until.as_secs()));
+        }
+        // Written aside and renamed so a crash can't leave half a file.
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, contents)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BanOptions {
+    pub threshold: u32,
+    pub duration: Duration,
+    /// Prefix length banned around a misbehaving IPv4 address.
+    pub ipv4_prefix: u8,
+    /// Prefix length banned around a misbehaving IPv6 address.
+    pub ipv6_prefix: u8,
+}
+
+impl Default for BanOptions {
+    fn default() -> Self {
+        Self {
+            threshold: BAN_THRESHOLD,
+            duration: Duration::from_secs(24 * 60 * 60),
+            ipv4_prefix: 32,
+            ipv6_prefix: 128,
+        }
+    }
+}
+
+/// Adds up misbehavior points per address and bans those crossing the
+/// threshold.
+#[derive(Debug)]
+pub struct BanManager {
+    options: BanOptions,
+    scores: HashMap<IpAddr, u32>,
+    bans: BanList,
+}
+
+impl BanManager {
+    pub fn new(bans: BanList, options: BanOptions) -> Self {
+        Self {
+            options,
+            scores: HashMap::new(),
+            bans,
+        }
+    }
+
+    /// Adds `points` to the score of `addr`. Returns true if that got it
+    /// banned, in which case the connection should be dropped.
+    pub fn misbehaving(&mut self, addr: IpAddr, points: u32) -> Result<bool> {
+        if points == 0 {
+            return Ok(false);
+        }
+        let addr = addr.to_canonical();
+        let score = self.scores.entry(addr).or_default();
+        *score = score.saturating_add(points);
+        if *score < self.options.threshold {
+            return Ok(false);
+        }
+        self.scores.remove(&addr);
+        let prefix = match addr {
+            IpAddr::V4(_) => self.options.ipv4_prefix,
+            IpAddr::V6(_) => self.options.ipv6_prefix,
+        };
+        self.bans
+            .add(Subnet::new(addr, prefix)?, self.options.duration)?;
+        Ok(true)
+    }
+
+    /// Scores an error caused by the peer at `addr`, see `Error::misbehavior`.
+    pub fn report(&mut self, addr: IpAddr, error: &Error) -> Result<bool> {
+        self.misbehaving(addr, error.misbehavior())
+    }
+
+    pub fn score(&self, addr: IpAddr) -> u32 {
+        self.scores
+            .get(&addr.to_canonical())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn is_banned(&self, addr: IpAddr) -> bool {
+        self.bans.is_banned(addr)
+    }
+
+    pub fn bans(&self) -> &BanList {
+        &self.bans
+    }
+
+    pub fn bans_mut(&mut self) -> &mut BanList {
+        &mut self.bans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::Violation;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn subnets() {
+        let subnet: Subnet = "203.0.113.77/24".parse().unwrap();
+        assert_eq!(subnet.to_string(), "203.0.113.0/24");
+        assert!(subnet.contains("203.0.113.1".parse().unwrap()));
+        assert!(subnet.contains("::ffff:203.0.113.1".parse().unwrap()));
+        assert!(!subnet.contains("203.0.114.1".parse().unwrap()));
+        assert!(!subnet.contains("2001:db8::1".parse().unwrap()));
+
+        let single: Subnet = "2001:db8::1".parse().unwrap();
+        assert_eq!(single.to_string(), "2001:db8::1/128");
+        assert!("10.0.0.0/33".parse::<Subnet>().is_err());
+    }
+
+    #[test]
+    fn bans_after_threshold_and_persists() {
+        let path = std::env::temp_dir().join(format!("banlist-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let addr: IpAddr = "198.51.100.7".parse().unwrap();
+
+        let mut manager = BanManager::new(
+            BanList::open(&path).unwrap(),
+            BanOptions {
+                ipv4_prefix: 24,
+                ..Default::default()
+            },
+        );
+        let checksum = Error::from(Violation::Checksum {
+            expected: 0,
+            actual: 1,
+        });
+        for _ in 0..9 {
+            assert!(!manager.report(addr, &checksum).unwrap());
+        }
+        assert_eq!(manager.score(addr), 90);
+        assert!(manager.report(addr, &checksum).unwrap());
+        assert!(manager.is_banned("198.51.100.200".parse().unwrap()));
+
+        let reopened = BanList::open(&path).unwrap();
+        assert_eq!(reopened.list().len(), 1);
+        assert!(reopened.is_banned(addr));
+
+        let mut bans = reopened;
+        assert!(bans.remove(&"198.51.100.0/24".parse().unwrap()).unwrap());
+        assert!(BanList::open(&path).unwrap().list().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
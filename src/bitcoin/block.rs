@@ -0,0 +1,323 @@
+use crate::bitcoin::{
+    BlockHash, Context, Decode, Encode, Error, Hash256, Network, Result, Transaction, Txid,
+    VariableInt,
+};
+use bytes::{Buf, BufMut, Bytes};
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockHeader {
+    pub version: i32,
+    pub prev_blockhash: BlockHash,
+    pub merkle_root: Hash256,
+    pub time: u32,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    pub const SIZE: usize = 80;
+
+    pub fn genesis(network: Network) -> Self {
+        let (time, bits, nonce) = match network {
+            Network::Mainnet => (1231006505, 0x1d00ffff, 2083236893),
+            Network::Testnet => (1296688602, 0x1d00ffff, 414098458),
+            Network::Signet => (1598918400, 0x1e0377ae, 52613770),
+            Network::Regtest => (1296688602, 0x207fffff, 2),
+        };
+        Self {
+            version: 1,
+            prev_blockhash: Hash256::default(),
+            merkle_root: "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"
+                .parse()
+                .unwrap(),
+            time,
+            bits,
+            nonce,
+        }
+    }
+
+    pub fn block_hash(&self) -> BlockHash {
+        let mut buffer = Vec::with_capacity(Self::SIZE);
+        self.encode(&mut buffer)
+            .expect("encoding into a vec cannot fail");
+        Hash256::hash(&buffer)
+    }
+
+    /// Expands the compact `bits` field into a big-endian 256-bit target.
+    pub fn target(&self) -> [u8; 32] {
+        let exponent = (self.bits >> 24) as i32;
+        let mantissa = (self.bits & 0x007fffff).to_be_bytes();
+        let mut target = [0; 32];
+        for (i, byte) in mantissa[1..].iter().enumerate() {
+            let power = exponent - 1 - i as i32;
+            if (0..32).contains(&power) {
+                target[31 - power as usize] = *byte;
+            }
+        }
+        target
+    }
+
+    /// Checks that the header hash satisfies its own claimed difficulty. This
+    /// does not verify that `bits` is the difficulty the chain requires.
+    pub fn check_proof_of_work(&self) -> bool {
+        let mut hash = *self.block_hash().as_bytes();
+        hash.reverse();
+        hash <= self.target()
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Block {
+    pub fn block_hash(&self) -> BlockHash {
+        self.header.block_hash()
+    }
+
+    pub fn compute_merkle_root(&self) -> Hash256 {
+        merkle_root(self.transactions.iter().map(Transaction::txid).collect())
+    }
+
+    pub fn check_merkle_root(&self) -> Result<()> {
+        if self.compute_merkle_root() != self.header.merkle_root {
+            return Err(Error::InvalidBlock(format!(
+                "merkle root mismatch in block {}",
+                self.block_hash()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A block whose transactions stay serialized in the frame they arrived in
+/// and are only parsed when asked for. Cloning shares the bytes.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LazyBlock {
+    header: BlockHeader,
+    /// Serialized transactions, without the leading count.
+    transactions: Bytes,
+    /// Where each transaction sits in `transactions`.
+    offsets: Vec<Range<usize>>,
+    /// Context the transactions were serialized with.
+    context: Context,
+}
+
+impl LazyBlock {
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    pub fn block_hash(&self) -> BlockHash {
+        self.header.block_hash()
+    }
+
+    pub fn transaction_count(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// The serialized transaction at `index`, sharing the block's bytes.
+    pub fn raw_transaction(&self, index: usize) -> Option<Bytes> {
+        let range = self.offsets.get(index)?;
+        Some(self.transactions.slice(range.clone()))
+    }
+
+    pub fn transaction(&self, index: usize) -> Option<Result<Transaction>> {
+        let mut raw = self.raw_transaction(index)?;
+        Some(Transaction::decode_with(&mut raw, &self.context))
+    }
+
+    pub fn transactions(&self) -> impl Iterator<Item = Result<Transaction>> + '_ {
+        (0..self.offsets.len()).filter_map(|index| self.transaction(index))
+    }
+
+    /// Transaction ids in block order. Transactions without witness data are
+    /// hashed straight from the frame.
+    pub fn txids(&self) -> Result<Vec<Txid>> {
+        self.offsets
+            .iter()
+            .map(|range| {
+                let raw = &self.transactions[range.clone()];
+                if self.context.witness && raw[4..].starts_with(&[0x00, 0x01]) {
+                    Ok(Transaction::decode_with(&mut &raw[..], &self.context)?.txid())
+                } else {
+                    Ok(Hash256::hash(raw))
+                }
+            })
+            .collect()
+    }
+
+    pub fn compute_merkle_root(&self) -> Result<Hash256> {
+        Ok(merkle_root(self.txids()?))
+    }
+
+    pub fn check_merkle_root(&self) -> Result<()> {
+        if self.compute_merkle_root()? != self.header.merkle_root {
+            return Err(Error::InvalidBlock(format!(
+                "merkle root mismatch in block {}",
+                self.block_hash()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Parses every transaction.
+    pub fn to_block(&self) -> Result<Block> {
+        Ok(Block {
+            header: self.header,
+            transactions: self.transactions().collect::<Result<_>>()?,
+        })
+    }
+}
+
+impl From<&Block> for LazyBlock {
+    fn from(block: &Block) -> Self {
+        let context = Context::default();
+        let mut transactions = Vec::new();
+        let mut offsets = Vec::with_capacity(block.transactions.len());
+        for transaction in &block.transactions {
+            let start = transactions.len();
+            transaction
+                .encode_with(&mut transactions, &context)
+                .expect("encoding into a vec cannot fail");
+            offsets.push(start..transactions.len());
+        }
+        Self {
+            header: block.header,
+            transactions: transactions.into(),
+            offsets,
+            context,
+        }
+    }
+}
+
+impl Encode for LazyBlock {
+    fn encode_with(&self, buffer: &mut impl BufMut, context: &Context) -> Result<usize> {
+        if context.witness != self.context.witness {
+            return self.to_block()?.encode_with(buffer, context);
+        }
+        let mut written = self.header.encode_with(buffer, context)?;
+        written += VariableInt(self.offsets.len() as u64).encode_with(buffer, context)?;
+        if buffer.remaining_mut() < self.transactions.len() {
+            return Err(Error::NotEnoughSpace("block"));
+        }
+        buffer.put_slice(&self.transactions);
+        Ok(written + self.transactions.len())
+    }
+}
+
+impl Decode for LazyBlock {
+    /// Only the transaction boundaries are found up front. The transactions
+    /// must be contiguous in `bytes`, which holds for `Bytes` frames from the
+    /// codec; taking them from a `Bytes` doesn't copy.
+    fn decode_with(bytes: &mut impl Buf, context: &Context) -> Result<Self> {
+        let header = BlockHeader::decode_with(bytes, context).map_err(|e| e.in_field("header"))?;
+        let count = VariableInt::decode_with(bytes, context)
+            .map_err(|e| e.in_field("transactions"))?
+            .0 as usize;
+        let chunk = bytes.chunk();
+        // Transactions are at least ten bytes, which bounds the count.
+        let mut offsets = Vec::with_capacity(count.min(chunk.len() / 10));
+        let mut end = 0;
+        for index in 0..count {
+            let length = Transaction::measure(&chunk[end..], context)
+                .map_err(|e| e.in_field(&format!("transactions.{}", index)))?;
+            offsets.push(end..end + length);
+            end += length;
+        }
+        Ok(Self {
+            header,
+            transactions: bytes.copy_to_bytes(end),
+            offsets,
+            context: *context,
+        })
+    }
+}
+
+fn merkle_root(mut hashes: Vec<Hash256>) -> Hash256 {
+    if hashes.is_empty() {
+        return Hash256::default();
+    }
+    while hashes.len() > 1 {
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| {
+                let right = pair.get(1).unwrap_or(&pair[0]);
+                Hash256::hash(&[&pair[0].as_bytes()[..], &right.as_bytes()[..]].concat())
+            })
+            .collect();
+    }
+    hashes[0]
+}
+
+impl Encode for Block {
+    fn encode_with(&self, buffer: &mut impl BufMut, context: &Context) -> Result<usize> {
+        let mut written = self.header.encode_with(buffer, context)?;
+        written += self.transactions.encode_with(buffer, context)?;
+        Ok(written)
+    }
+}
+
+impl Decode for Block {
+    fn decode_with(bytes: &mut impl Buf, context: &Context) -> Result<Self> {
+        let header = BlockHeader::decode_with(bytes, context).map_err(|e| e.in_field("header"))?;
+        let transactions =
+            Vec::decode_with(bytes, context).map_err(|e| e.in_field("transactions"))?;
+        Ok(Block {
+            header,
+            transactions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::{OutPoint, TxIn, TxOut};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn lazy_block_shares_frame() {
+        let spend = |witness: Vec<Vec<u8>>| Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Hash256::hash(b"previous"),
+                    vout: 0,
+                },
+                witness,
+                ..Default::default()
+            }],
+            outputs: vec![TxOut {
+                value: 1000,
+                script_pubkey: vec![0x51].into(),
+            }],
+            lock_time: 0,
+        };
+        let mut block = Block {
+            header: BlockHeader::genesis(Network::Regtest),
+            transactions: vec![spend(vec![]), spend(vec![vec![0x30; 72]])],
+        };
+        block.header.merkle_root = block.compute_merkle_root();
+
+        let mut encoded = vec![];
+        block.encode(&mut encoded).unwrap();
+        let frame = Bytes::from(encoded);
+        let lazy = LazyBlock::decode(&mut frame.clone()).unwrap();
+
+        assert_eq!(lazy.transaction_count(), 2);
+        let raw = lazy.raw_transaction(1).unwrap();
+        assert!(frame.as_ptr_range().contains(&raw.as_ptr()));
+        lazy.check_merkle_root().unwrap();
+        assert_eq!(lazy.to_block().unwrap(), block);
+
+        let mut reencoded = vec![];
+        lazy.encode(&mut reencoded).unwrap();
+        assert_eq!(reencoded, frame);
+    }
+}
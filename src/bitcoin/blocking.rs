@@ -0,0 +1,277 @@
+use crate::bitcoin::peer::{negotiate, Handshake, PeerConfig};
+use crate::bitcoin::{
+    BitcoinCodec, CodecEvent, Context, Error, Message, Network, Payload, Result, VersionMessage,
+};
+use bytes::BytesMut;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+use tokio_util::codec::{Decoder, Encoder};
+
+const READ_SIZE: usize = 8 * 1024;
+
+/// A blocking connection to a remote node, framed by the same codec as the
+/// async `Peer`. Call `handshake` before anything else.
+pub struct SyncPeer {
+    addr: SocketAddr,
+    network: Network,
+    stream: TcpStream,
+    codec: BitcoinCodec,
+    /// Bytes read but not decoded yet.
+    buffer: BytesMut,
+    config: PeerConfig,
+    version: Option<VersionMessage>,
+    skipped: usize,
+}
+
+impl SyncPeer {
+    pub fn connect(addr: SocketAddr, config: &PeerConfig) -> Result<Self> {
+        Self::from_stream(TcpStream::connect(addr)?, config)
+    }
+
+    /// Wraps an already connected stream.
+    pub fn from_stream(stream: TcpStream, config: &PeerConfig) -> Result<Self> {
+        let addr = stream.peer_addr()?;
+        Ok(Self {
+            addr,
+            network: config.network,
+            stream,
+            codec: config.codec(addr)?,
+            buffer: BytesMut::new(),
+            config: config.clone(),
+            version: None,
+            skipped: 0,
+        })
+    }
+
+    /// How long `recv` waits for data before failing with a timed out IO
+    /// error. `None` waits forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.stream.set_read_timeout(timeout)?)
+    }
+
+    /// Exchanges version and verack messages, then switches to the
+    /// negotiated context.
+    pub fn handshake(&mut self) -> Result<()> {
+        if self.version.is_some() {
+            return Err(Error::Handshake("handshake already done".to_string()));
+        }
+        let (mut handshake, version) = Handshake::start(&self.config, self.addr);
+        self.send(version)?;
+
+        while !handshake.is_done() {
+            if let Some(reply) = handshake.receive(self.read_message()?.into_payload())? {
+                self.send(reply)?;
+            }
+        }
+
+        let version = handshake.finish().unwrap();
+        let context = negotiate(self.codec.context(), &version);
+        self.codec.set_context(context);
+        self.version = Some(version);
+        Ok(())
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn context(&self) -> &Context {
+        self.codec.context()
+    }
+
+    /// The version message the remote node sent, once the handshake is done.
+    pub fn version(&self) -> Option<&VersionMessage> {
+        self.version.as_ref()
+    }
+
+    /// Bytes dropped so far because they didn't form valid frames.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    pub fn send(&mut self, payload: Payload) -> Result<()> {
+        let message = Message::new(self.network.magic(), payload.command(), payload)?;
+        let mut frame = BytesMut::new();
+        self.codec.encode(message, &mut frame)?;
+        self.stream.write_all(&frame)?;
+        Ok(())
+    }
+
+    /// Waits for the next message from the peer. Pings are answered transparently.
+    /// Like `Peer::recv`, a rule broken by bytes that had to be skipped is
+    /// returned once as an error and receiving can go on, while after any
+    /// other error the peer is to blame for the connection is shut down.
+    pub fn recv(&mut self) -> Result<Payload> {
+        loop {
+            match self.read_message()?.into_payload() {
+                Payload::Ping(nonce) => self.send(Payload::Pong(nonce))?,
+                payload => return Ok(payload),
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Message> {
+        let mut eof = false;
+        loop {
+            let event = if eof {
+                self.codec.decode_eof(&mut self.buffer)
+            } else {
+                self.codec.decode(&mut self.buffer)
+            };
+            match event {
+                Ok(Some(CodecEvent::Message(message))) => return Ok(message),
+                Ok(Some(CodecEvent::Resynced { skipped, cause })) => {
+                    self.skipped += skipped;
+                    match cause {
+                        Some(violation) => return Err(violation.into()),
+                        None => continue,
+                    }
+                }
+                Ok(None) if eof => return Err(Error::ConnectionClosed),
+                Ok(None) => {}
+                Err(e) => {
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                    self.buffer.clear();
+                    return Err(e);
+                }
+            }
+            let mut chunk = [0; READ_SIZE];
+            let read = self.stream.read(&mut chunk)?;
+            eof = read == 0;
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::capture::{peer_directory, read_peer, Direction};
+    use crate::bitcoin::{Encode, ErrorKind, Violation};
+    use pretty_assertions::assert_eq;
+    use std::net::TcpListener;
+
+    /// A `SyncPeer` on mainnet, and the raw stream of the node it talks to.
+    fn connected() -> (SyncPeer, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut peer = SyncPeer::from_stream(stream, &PeerConfig::default()).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (peer, node)
+    }
+
+    fn frame(network: Network, payload: Payload) -> Vec<u8> {
+        let mut frame = vec![];
+        Message::new(network.magic(), payload.command(), payload)
+            .unwrap()
+            .encode(&mut frame)
+            .unwrap();
+        frame
+    }
+
+    #[test]
+    fn handshake_and_ping() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = PeerConfig::default();
+        let node = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut node = SyncPeer::from_stream(stream, &PeerConfig::default()).unwrap();
+            node.handshake().unwrap();
+            node.send(Payload::Ping(7)).unwrap();
+            node.recv().unwrap()
+        });
+
+        let mut peer = SyncPeer::connect(addr, &config).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        peer.handshake().unwrap();
+        assert_eq!(
+            peer.version().unwrap().user_agent,
+            PeerConfig::default().user_agent.as_str().into()
+        );
+        assert!(peer.handshake().is_err());
+
+        // The ping is answered inside `recv`, which then times out.
+        peer.set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let error = peer.recv().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Io);
+        assert_eq!(node.join().unwrap(), Payload::Pong(7));
+    }
+
+    #[test]
+    fn captures_both_directions() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let root = std::env::temp_dir().join(format!("sync-capture-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let config = PeerConfig {
+            capture: Some(root.clone()),
+            ..PeerConfig::default()
+        };
+        let node = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut node = SyncPeer::from_stream(stream, &PeerConfig::default()).unwrap();
+            node.handshake().unwrap();
+        });
+
+        let mut peer = SyncPeer::connect(addr, &config).unwrap();
+        peer.handshake().unwrap();
+        node.join().unwrap();
+
+        let records = read_peer(peer_directory(&root, addr)).unwrap();
+        for direction in [Direction::Outbound, Direction::Inbound] {
+            let commands = records
+                .iter()
+                .filter(|record| record.direction == direction)
+                .map(|record| record.command.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(commands, ["version", "verack"], "{:?}", direction);
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn skips_invalid_frames() {
+        let (mut peer, mut node) = connected();
+        let testnet = frame(Network::Testnet, Payload::SendHeaders);
+        node.write_all(&testnet).unwrap();
+        node.write_all(&frame(Network::Mainnet, Payload::SendHeaders))
+            .unwrap();
+        // Half a magic, which only the end of the stream tells is garbage.
+        node.write_all(&Network::Mainnet.magic().to_le_bytes()[..2])
+            .unwrap();
+        node.shutdown(Shutdown::Write).unwrap();
+
+        assert!(matches!(
+            peer.recv(),
+            Err(Error::Protocol(Violation::WrongMagic { .. }))
+        ));
+        assert_eq!(peer.skipped(), testnet.len());
+        assert_eq!(peer.recv().unwrap(), Payload::SendHeaders);
+        assert!(matches!(peer.recv(), Err(Error::ConnectionClosed)));
+        assert_eq!(peer.skipped(), testnet.len() + 2);
+    }
+
+    #[test]
+    fn closes_after_a_fatal_error() {
+        let (mut peer, mut node) = connected();
+        let mut header = Network::Mainnet.magic().to_le_bytes().to_vec();
+        header.extend_from_slice(b"block\0\0\0\0\0\0\0");
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        header.extend_from_slice(&[0; 4]);
+        node.write_all(&header).unwrap();
+
+        assert!(matches!(
+            peer.recv(),
+            Err(Error::Protocol(Violation::Oversize { .. }))
+        ));
+        assert!(matches!(peer.recv(), Err(Error::ConnectionClosed)));
+    }
+}
@@ -0,0 +1,319 @@
+use crate::bitcoin::peer::{Peer, PeerConfig, Transport};
+use crate::bitcoin::{
+    Inventory, InventoryKind, Payload, Result, ServiceFlags, Transaction, Txid, Wtxid,
+};
+use futures::future::select_all;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+
+#[derive(Debug, Clone)]
+pub struct BroadcastOptions {
+    /// How many of the given peers get the `inv`; the rest only listen for
+    /// the transaction coming back.
+    pub announce_to: usize,
+    /// Announcements from distinct non-announced peers needed for success.
+    pub confirmations: usize,
+    pub timeout: Duration,
+    /// Announce over a separate short-lived connection to this node instead
+    /// of our long-lived peers, so they can't link the transaction to us.
+    pub via: Option<SocketAddr>,
+}
+
+impl Default for BroadcastOptions {
+    fn default() -> Self {
+        Self {
+            announce_to: 2,
+            confirmations: 1,
+            timeout: Duration::from_secs(60),
+            via: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct BroadcastReport {
+    pub txid: Txid,
+    pub announced_to: HashSet<SocketAddr>,
+    /// Peers which fetched the transaction from us with `getdata`.
+    pub requested_by: HashSet<SocketAddr>,
+    /// Peers we did not announce to which announced the transaction to us.
+    pub seen_from: HashSet<SocketAddr>,
+}
+
+impl BroadcastReport {
+    pub fn succeeded(&self, confirmations: usize) -> bool {
+        self.seen_from.len() >= confirmations.max(1)
+    }
+}
+
+/// State of a single transaction broadcast, independent of how peers are driven.
+#[derive(Debug, Clone)]
+pub struct Broadcast {
+    tx: Transaction,
+    wtxid: Wtxid,
+    report: BroadcastReport,
+}
+
+impl Broadcast {
+    pub fn new(tx: Transaction) -> Self {
+        let report = BroadcastReport {
+            txid: tx.txid(),
+            ..Default::default()
+        };
+        Self {
+            wtxid: tx.wtxid(),
+            tx,
+            report,
+        }
+    }
+
+    /// Returns the `inv` to send to `peer` and remembers it was announced there.
+    pub fn announce(&mut self, peer: SocketAddr) -> Payload {
+        self.report.announced_to.insert(peer);
+        Payload::Inv(vec![Inventory {
+            kind: InventoryKind::Tx,
+            hash: self.report.txid,
+        }])
+    }
+
+    fn matches(&self, inv: &Inventory) -> bool {
+        match inv.kind {
+            InventoryKind::Tx | InventoryKind::WitnessTx => inv.hash == self.report.txid,
+            InventoryKind::Wtx => inv.hash == self.wtxid,
+            _ => false,
+        }
+    }
+
+    /// Feeds a payload received from `peer`, returning the transaction if the
+    /// peer asked for it.
+    pub fn handle(&mut self, peer: SocketAddr, payload: &Payload) -> Option<Payload> {
+        match payload {
+            Payload::GetData(inventory) if inventory.iter().any(|inv| self.matches(inv)) => {
+                self.report.requested_by.insert(peer);
+                Some(Payload::Tx(self.tx.clone()))
+            }
+            Payload::Inv(inventory) if inventory.iter().any(|inv| self.matches(inv)) => {
+                if !self.report.announced_to.contains(&peer) {
+                    self.report.seen_from.insert(peer);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    pub fn report(&self) -> &BroadcastReport {
+        &self.report
+    }
+
+    pub fn into_report(self) -> BroadcastReport {
+        self.report
+    }
+}
+
+/// A peer taking part in a broadcast: one of the caller's, or the connection
+/// we made to announce through `BroadcastOptions::via`.
+enum Participant<'a, T> {
+    Peer(&'a mut Peer<T>),
+    Messenger(&'a mut Peer),
+}
+
+impl<T: Transport> Participant<'_, T> {
+    fn addr(&self) -> SocketAddr {
+        match self {
+            Self::Peer(peer) => peer.addr(),
+            Self::Messenger(peer) => peer.addr(),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<Payload> {
+        match self {
+            Self::Peer(peer) => peer.recv().await,
+            Self::Messenger(peer) => peer.recv().await,
+        }
+    }
+
+    async fn send(&mut self, payload: Payload) -> Result<()> {
+        match self {
+            Self::Peer(peer) => peer.send(payload).await,
+            Self::Messenger(peer) => peer.send(payload).await,
+        }
+    }
+}
+
+/// Announces `tx` and serves it to whoever asks, until it has been announced
+/// back to us by `options.confirmations` other peers or the timeout expires.
+/// The listening peers should have been connected with `relay` enabled.
+pub async fn broadcast<T: Transport>(
+    tx: Transaction,
+    peers: &mut [Peer<T>],
+    config: &PeerConfig,
+    options: &BroadcastOptions,
+) -> Result<BroadcastReport> {
+    let mut state = Broadcast::new(tx);
+    let deadline = Instant::now() + options.timeout;
+
+    let mut messenger = None;
+    match options.via {
+        Some(addr) => {
+            let config = PeerConfig {
+                relay: false,
+                services: ServiceFlags::NONE,
+                start_height: 0,
+                ..config.clone()
+            };
+            let mut peer = Peer::connect(addr, &config).await?;
+            peer.send(state.announce(addr)).await?;
+            messenger = Some(peer);
+        }
+        None => {
+            let announce = options.announce_to.min(peers.len());
+            for peer in peers[..announce].iter_mut() {
+                let payload = state.announce(peer.addr());
+                peer.send(payload).await?;
+            }
+        }
+    }
+
+    let mut active = peers
+        .iter_mut()
+        .map(Participant::Peer)
+        .chain(messenger.as_mut().map(Participant::Messenger))
+        .collect::<Vec<_>>();
+    while !state.report().succeeded(options.confirmations) && !active.is_empty() {
+        let received = select_all(active.iter_mut().map(|peer| {
+            Box::pin(async move {
+                let addr = peer.addr();
+                (addr, peer.recv().await)
+            })
+        }));
+        let ((addr, payload), index, rest) = match timeout_at(deadline, received).await {
+            Ok(received) => received,
+            Err(_) => break,
+        };
+        drop(rest);
+
+        match payload {
+            Ok(payload) => {
+                if let Some(reply) = state.handle(addr, &payload) {
+                    if active[index].send(reply).await.is_err() {
+                        active.swap_remove(index);
+                    }
+                }
+            }
+            Err(_) => {
+                active.swap_remove(index);
+            }
+        }
+    }
+
+    Ok(state.into_report())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::mock::{Action, MockConfig, MockNode};
+    use crate::bitcoin::{Command, Network, TxOut};
+    use pretty_assertions::assert_eq;
+
+    fn tx() -> Transaction {
+        Transaction {
+            version: 1,
+            inputs: vec![],
+            outputs: vec![TxOut {
+                value: 1,
+                script_pubkey: vec![0x51].into(),
+            }],
+            lock_time: 0,
+        }
+    }
+
+    #[test]
+    fn serves_and_confirms() {
+        let tx = tx();
+        let a: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:8333".parse().unwrap();
+        let txid = tx.txid();
+
+        let mut state = Broadcast::new(tx.clone());
+        let announcement = state.announce(a);
+        let Payload::Inv(inventory) = &announcement else {
+            panic!("expected inv");
+        };
+        let getdata = Payload::GetData(vec![Inventory {
+            kind: InventoryKind::WitnessTx,
+            hash: inventory[0].hash,
+        }]);
+        assert_eq!(state.handle(a, &getdata), Some(Payload::Tx(tx)));
+
+        // Echoes from the peer we told don't count.
+        state.handle(a, &announcement);
+        assert!(!state.report().succeeded(1));
+
+        state.handle(b, &announcement);
+        let report = state.into_report();
+        assert!(report.succeeded(1));
+        assert_eq!(report.txid, txid);
+        assert_eq!(report.requested_by, HashSet::from([a]));
+        assert_eq!(report.seen_from, HashSet::from([b]));
+    }
+
+    #[tokio::test]
+    async fn broadcasts_over_any_transport() {
+        let tx = tx();
+        let inv = Payload::Inv(vec![Inventory {
+            kind: InventoryKind::Tx,
+            hash: tx.txid(),
+        }]);
+        // The first node fetches the transaction we announce; the second
+        // announces it back a little later, as if it had been relayed.
+        let scripts = [
+            vec![
+                Action::Expect(Command::Inv),
+                Action::Send(Payload::GetData(vec![Inventory {
+                    kind: InventoryKind::WitnessTx,
+                    hash: tx.txid(),
+                }])),
+                Action::Expect(Command::Tx),
+            ],
+            vec![Action::Delay(Duration::from_millis(200)), Action::Send(inv)],
+        ];
+        let config = PeerConfig {
+            network: Network::Regtest,
+            relay: true,
+            ..Default::default()
+        };
+        let mut peers = vec![];
+        let mut nodes = vec![];
+        for (index, script) in scripts.into_iter().enumerate() {
+            let (ours, theirs) = tokio::io::duplex(1 << 16);
+            let node = MockNode::new(MockConfig {
+                script,
+                ..Default::default()
+            });
+            nodes.push(tokio::spawn(async move { node.serve(theirs).await }));
+            let addr = SocketAddr::from(([10, 0, 0, index as u8 + 1], 18444));
+            peers.push(Peer::handshake(ours, addr, &config).await.unwrap());
+        }
+        let (a, b) = (peers[0].addr(), peers[1].addr());
+
+        let options = BroadcastOptions {
+            announce_to: 1,
+            timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let report = broadcast(tx.clone(), &mut peers, &config, &options)
+            .await
+            .unwrap();
+        assert_eq!(report.announced_to, HashSet::from([a]));
+        assert_eq!(report.requested_by, HashSet::from([a]));
+        assert_eq!(report.seen_from, HashSet::from([b]));
+
+        drop(peers);
+        let received = nodes.remove(0).await.unwrap().unwrap();
+        assert!(received.contains(&Payload::Tx(tx)));
+    }
+}
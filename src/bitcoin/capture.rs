@@ -0,0 +1,361 @@
+//! Message captures in the layout of Bitcoin Core's `-capturemessages`: a
+//! directory per peer, named after its address with `:` replaced by `_`,
+//! holding `msgs_recv.dat` and `msgs_sent.dat`. Each record is the time in
+//! microseconds since the epoch (u64), the command padded to 12 bytes, the
+//! payload length (u32) and the payload, all little-endian.
+//!
+//! Received bytes which didn't form a valid frame, like garbage or frames
+//! with a bad checksum, are recorded as they came under an empty command,
+//! which no real message has.
+
+use crate::bitcoin::peer::negotiate;
+use crate::bitcoin::{
+    BitcoinCodec, Checksum, CodecEvent, Context, Error, Message, Payload, Result, HEADER_SIZE,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_util::codec::Decoder;
+
+const COMMAND_SIZE: usize = 12;
+
+/// time (8) + command (12) + length (4)
+const RECORD_HEADER_SIZE: usize = 24;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    fn file_name(&self) -> &'static str {
+        match self {
+            Self::Inbound => "msgs_recv.dat",
+            Self::Outbound => "msgs_sent.dat",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CaptureRecord {
+    pub time: SystemTime,
+    pub direction: Direction,
+    pub peer: SocketAddr,
+    /// Command name without padding, kept even if we don't know it. Empty
+    /// for raw bytes, see `is_raw`.
+    pub command: String,
+    pub payload: Bytes,
+}
+
+impl CaptureRecord {
+    /// Whether the record holds received bytes that weren't a valid frame.
+    pub fn is_raw(&self) -> bool {
+        self.command.is_empty()
+    }
+
+    /// Rebuilds the frame the record was taken from. Captures don't keep the
+    /// header, so `magic` has to be supplied and the checksum is recomputed.
+    /// Raw records are returned as they are.
+    pub fn to_frame(&self, magic: u32) -> BytesMut {
+        if self.is_raw() {
+            return BytesMut::from(&self.payload[..]);
+        }
+        let mut frame = BytesMut::with_capacity(HEADER_SIZE + self.payload.len());
+        frame.put_u32_le(magic);
+        frame.put_slice(&padded(&self.command));
+        frame.put_u32_le(self.payload.len() as u32);
+        frame.put_u32_le(self.payload.sha256());
+        frame.put_slice(&self.payload);
+        frame
+    }
+}
+
+fn padded(command: &str) -> [u8; COMMAND_SIZE] {
+    let mut padded = [0; COMMAND_SIZE];
+    let length = command.len().min(COMMAND_SIZE);
+    padded[..length].copy_from_slice(&command.as_bytes()[..length]);
+    padded
+}
+
+/// Directory the messages exchanged with `peer` are captured in.
+pub fn peer_directory(root: impl AsRef<Path>, peer: SocketAddr) -> PathBuf {
+    root.as_ref().join(peer.to_string().replace(':', "_"))
+}
+
+/// Appends the messages of a single connection to its capture files.
+#[derive(Debug)]
+pub struct PeerCapture {
+    peer: SocketAddr,
+    inbound: File,
+    outbound: File,
+}
+
+impl PeerCapture {
+    /// Opens the capture files of `peer` under `root`, which is what Core
+    /// calls `message_capture`. Existing captures are appended to.
+    pub fn open(root: impl AsRef<Path>, peer: SocketAddr) -> Result<Self> {
+        let directory = peer_directory(root, peer);
+        std::fs::create_dir_all(&directory)?;
+        let open = |direction: Direction| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(directory.join(direction.file_name()))
+        };
+        Ok(Self {
+            peer,
+            inbound: open(Direction::Inbound)?,
+            outbound: open(Direction::Outbound)?,
+        })
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Records a whole frame, header included, as sent or received now.
+    pub fn record_frame(&mut self, direction: Direction, frame: &[u8]) -> Result<()> {
+        if frame.len() < HEADER_SIZE {
+            return Err(Error::NotEnoughBytes("frame"));
+        }
+        let command = frame[4..4 + COMMAND_SIZE].try_into()?;
+        self.record(direction, SystemTime::now(), command, &frame[HEADER_SIZE..])
+    }
+
+    /// Records bytes which didn't form a valid frame, as sent or received
+    /// now.
+    pub fn record_raw(&mut self, direction: Direction, bytes: &[u8]) -> Result<()> {
+        self.record(direction, SystemTime::now(), [0; COMMAND_SIZE], bytes)
+    }
+
+    pub fn record(
+        &mut self,
+        direction: Direction,
+        time: SystemTime,
+        command: [u8; COMMAND_SIZE],
+        payload: &[u8],
+    ) -> Result<()> {
+        let micros = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        record.put_u64_le(micros);
+        record.put_slice(&command);
+        record.put_u32_le(payload.len() as u32);
+        record.put_slice(payload);
+        // One write per record, so concurrent readers never see half of one.
+        let file = match direction {
+            Direction::Inbound => &mut self.inbound,
+            Direction::Outbound => &mut self.outbound,
+        };
+        file.write_all(&record)?;
+        Ok(())
+    }
+}
+
+/// Reads one capture file. The direction and peer come from its path.
+pub fn read_file(path: impl AsRef<Path>) -> Result<Vec<CaptureRecord>> {
+    let path = path.as_ref();
+    let direction = match path.file_name().and_then(|name| name.to_str()) {
+        Some("msgs_recv.dat") => Direction::Inbound,
+        Some("msgs_sent.dat") => Direction::Outbound,
+        _ => {
+            return Err(Error::Parse(format!(
+                "not a capture file: {}",
+                path.display()
+            )))
+        }
+    };
+    let peer = path
+        .parent()
+        .and_then(|directory| directory.file_name())
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.replace('_', ":").parse().ok())
+        .ok_or_else(|| Error::Parse(format!("no peer address in {}", path.display())))?;
+
+    let mut contents = Bytes::from(std::fs::read(path)?);
+    let mut records = Vec::new();
+    while !contents.is_empty() {
+        if contents.len() < RECORD_HEADER_SIZE {
+            return Err(Error::NotEnoughBytes("capture record"));
+        }
+        let header = contents.split_to(RECORD_HEADER_SIZE);
+        let micros = u64::from_le_bytes(header[..8].try_into()?);
+        let command = &header[8..8 + COMMAND_SIZE];
+        let length = u32::from_le_bytes(header[8 + COMMAND_SIZE..].try_into()?) as usize;
+        if contents.len() < length {
+            return Err(Error::NotEnoughBytes("capture payload"));
+        }
+        let end = command.iter().position(|b| *b == 0).unwrap_or(COMMAND_SIZE);
+        records.push(CaptureRecord {
+            time: UNIX_EPOCH + Duration::from_micros(micros),
+            direction,
+            peer,
+            command: std::str::from_utf8(&command[..end])?.to_string(),
+            payload: contents.split_to(length),
+        });
+    }
+    Ok(records)
+}
+
+/// Reads both capture files of a peer directory, merged in time order.
+/// A missing file counts as empty.
+pub fn read_peer(directory: impl AsRef<Path>) -> Result<Vec<CaptureRecord>> {
+    let mut records = Vec::new();
+    for direction in [Direction::Inbound, Direction::Outbound] {
+        let path = directory.as_ref().join(direction.file_name());
+        if path.exists() {
+            records.extend(read_file(path)?);
+        }
+    }
+    records.sort_by_key(|record| record.time);
+    Ok(records)
+}
+
+/// Feeds captured messages through `BitcoinCodec` in order, as if they had
+/// just arrived. Once the remote node's version is seen, later messages are
+/// decoded with the negotiated context, like a `Peer` would.
+pub struct Replay<'a> {
+    records: std::slice::Iter<'a, CaptureRecord>,
+    codec: BitcoinCodec,
+}
+
+impl<'a> Replay<'a> {
+    pub fn new(records: &'a [CaptureRecord], context: Context) -> Self {
+        Self {
+            records: records.iter(),
+            codec: BitcoinCodec::new(context),
+        }
+    }
+}
+
+impl<'a> Iterator for Replay<'a> {
+    /// `None` for messages the codec skips, like unknown commands.
+    type Item = (&'a CaptureRecord, Result<Option<Message>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.records.next()?;
+        let mut frame = record.to_frame(self.codec.context().network.magic());
+        let message = match self.codec.decode(&mut frame) {
+            Ok(Some(CodecEvent::Message(message))) => Ok(Some(message)),
+            Ok(_) => Ok(None),
+            Err(e) => Err(e),
+        };
+        if let (Direction::Inbound, Ok(Some(message))) = (record.direction, &message) {
+            if let Payload::Version(version) = message.payload() {
+                let context = negotiate(self.codec.context(), version);
+                self.codec.set_context(context);
+            }
+        }
+        Some((record, message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::{Command, Encode, Violation};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn capture_and_replay() {
+        let root = std::env::temp_dir().join(format!("capture-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let peer: SocketAddr = "[2001:db8::1]:8333".parse().unwrap();
+        let magic = Context::default().network.magic();
+
+        let mut capture = PeerCapture::open(&root, peer).unwrap();
+        let ping = Message::new(magic, Command::Ping, Payload::Ping(42)).unwrap();
+        let mut frame = vec![];
+        ping.encode(&mut frame).unwrap();
+        capture.record_frame(Direction::Outbound, &frame).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(1);
+        capture
+            .record(Direction::Inbound, later, *b"wtfmsg\0\0\0\0\0\0", b"??")
+            .unwrap();
+
+        let directory = peer_directory(&root, peer);
+        assert!(directory.ends_with("[2001_db8__1]_8333"));
+        let records = read_peer(&directory).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Outbound);
+        assert_eq!(records[0].peer, peer);
+        assert_eq!(&records[0].to_frame(magic)[..], &frame[..]);
+        assert_eq!(records[1].command, "wtfmsg");
+
+        let replayed = Replay::new(&records, Context::default())
+            .map(|(_, message)| message.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(replayed, vec![Some(ping), None]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn captures_invalid_bytes() {
+        let root = std::env::temp_dir().join(format!("capture-raw-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let peer: SocketAddr = "192.0.2.1:8333".parse().unwrap();
+        let magic = Context::default().network.magic();
+
+        let mut codec = BitcoinCodec::default();
+        codec.set_capture(Some(PeerCapture::open(&root, peer).unwrap()));
+        let mut corrupt = vec![];
+        Message::new(magic, Command::Ping, Payload::Ping(1))
+            .unwrap()
+            .encode(&mut corrupt)
+            .unwrap();
+        corrupt[HEADER_SIZE] ^= 0xff;
+        // A header announcing a payload over the limit.
+        let mut oversize = magic.to_le_bytes().to_vec();
+        oversize.extend_from_slice(b"block\0\0\0\0\0\0\0");
+        oversize.extend_from_slice(&u32::MAX.to_le_bytes());
+        oversize.extend_from_slice(&[0; 4]);
+        let ping = Message::new(magic, Command::Ping, Payload::Ping(2)).unwrap();
+        let mut src = BytesMut::from(&b"junk"[..]);
+        src.extend_from_slice(&corrupt);
+        // Blamed while resyncing after the corrupt frame, an error after a
+        // valid one.
+        src.extend_from_slice(&oversize);
+        ping.encode(&mut src).unwrap();
+        src.extend_from_slice(&oversize);
+
+        assert!(matches!(
+            codec.decode(&mut src),
+            Ok(Some(CodecEvent::Resynced { skipped: 4, .. }))
+        ));
+        assert!(matches!(
+            codec.decode(&mut src),
+            Ok(Some(CodecEvent::Resynced { .. }))
+        ));
+        assert!(matches!(
+            codec.decode(&mut src),
+            Ok(Some(CodecEvent::Resynced {
+                cause: Some(Violation::Oversize { .. }),
+                ..
+            }))
+        ));
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(CodecEvent::Message(ping))
+        );
+        assert!(codec.decode(&mut src).is_err());
+        // The header is dropped, and recorded, only once.
+        assert!(src.is_empty());
+        assert!(matches!(codec.decode(&mut src), Ok(None)));
+
+        let records = read_peer(peer_directory(&root, peer)).unwrap();
+        let bytes = records
+            .iter()
+            .filter(|record| record.is_raw())
+            .flat_map(|record| record.to_frame(magic))
+            .collect::<Vec<_>>();
+        let expected = [&b"junk"[..], &corrupt, &oversize, &oversize].concat();
+        assert_eq!(bytes, expected);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,35 +1,382 @@
-pub use super::{Decode, Encode, Error, Message, Result};
+pub use super::{Checksum, Context, Error, Message, Result, Violation};
+use crate::bitcoin::capture::{Direction, PeerCapture};
 use bytes::{Buf, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-pub struct BitcoinCodec;
+/// magic (4) + command (12) + length (4) + checksum (4)
+pub const HEADER_SIZE: usize = 24;
+
+const MAGIC_SIZE: usize = 4;
+
+/// What the codec read from the stream.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CodecEvent {
+    Message(Message),
+    /// Bytes which weren't part of a valid frame were dropped to get to the
+    /// next network magic. `cause` is the rule the peer broke first, when
+    /// there's one to blame: a frame with another network's magic, a bad
+    /// checksum, or a length over the limit after garbage. Whether the connection is still worth keeping is up to the
+    /// caller, see `Error::misbehavior`.
+    Resynced {
+        skipped: usize,
+        cause: Option<Violation>,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct BitcoinCodec {
+    context: Context,
+    /// Bytes dropped since the last frame, not reported yet.
+    skipped: usize,
+    /// Why the first of those bytes were dropped.
+    cause: Option<Violation>,
+    /// Whether we're in the middle of garbage, rather than where a frame
+    /// should start.
+    resyncing: bool,
+    capture: Option<PeerCapture>,
+    /// The skipped bytes themselves, kept for the capture only.
+    dropped: BytesMut,
+}
+
+impl BitcoinCodec {
+    pub fn new(context: Context) -> Self {
+        Self {
+            context,
+            skipped: 0,
+            cause: None,
+            resyncing: false,
+            capture: None,
+            dropped: BytesMut::new(),
+        }
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Switches to the state negotiated during the handshake.
+    pub fn set_context(&mut self, context: Context) {
+        self.context = context;
+    }
+
+    /// Records every frame sent or received from now on, see `capture`.
+    /// Received bytes are recorded before they're validated, so garbage and
+    /// invalid frames end up in the capture too.
+    pub fn set_capture(&mut self, capture: Option<PeerCapture>) {
+        self.capture = capture;
+    }
+}
+
+impl BitcoinCodec {
+    /// Drops bytes up to the next network magic. Returns false if there
+    /// isn't a whole one yet; a trailing partial magic is kept.
+    fn seek_magic(&mut self, src: &mut BytesMut) -> bool {
+        let magic = self.context.network.magic().to_le_bytes();
+        // Where a frame should start, anything but our magic is a frame for
+        // the wrong network.
+        if !self.resyncing && src.len() >= MAGIC_SIZE && src[..MAGIC_SIZE] != magic {
+            self.blame(Violation::WrongMagic {
+                expected: self.context.network.magic(),
+                actual: u32::from_le_bytes(src[..MAGIC_SIZE].try_into().unwrap()),
+            });
+        }
+        let found = src.windows(MAGIC_SIZE).position(|window| window == magic);
+        let drop = found.unwrap_or_else(|| {
+            let partial = (1..MAGIC_SIZE)
+                .rev()
+                .find(|&length| src.ends_with(&magic[..length]))
+                .unwrap_or(0);
+            src.len() - partial.min(src.len())
+        });
+        self.skip(src, drop);
+        found.is_some()
+    }
+
+    fn skip(&mut self, src: &mut BytesMut, count: usize) {
+        if self.capture.is_some() {
+            self.dropped.extend_from_slice(&src[..count]);
+        }
+        src.advance(count);
+        self.skipped += count;
+        self.resyncing |= count > 0;
+    }
+
+    /// Keeps the first violation until it's reported with the skipped bytes.
+    fn blame(&mut self, violation: Violation) {
+        self.cause.get_or_insert(violation);
+    }
+
+    fn resynced(&mut self) -> Result<CodecEvent> {
+        self.capture_dropped()?;
+        Ok(CodecEvent::Resynced {
+            skipped: std::mem::take(&mut self.skipped),
+            cause: self.cause.take(),
+        })
+    }
+
+    fn capture_dropped(&mut self) -> Result<()> {
+        let dropped = self.dropped.split();
+        match &mut self.capture {
+            Some(capture) if !dropped.is_empty() => {
+                capture.record_raw(Direction::Inbound, &dropped)
+            }
+            _ => Ok(()),
+        }
+    }
+}
 
 impl Encoder<Message> for BitcoinCodec {
     type Error = Error;
 
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<()> {
-        item.encode(dst)?;
+        let start = dst.len();
+        item.encode_into(dst, &self.context)?;
+        if let Some(capture) = &mut self.capture {
+            capture.record_frame(Direction::Outbound, &dst[start..])?;
+        }
         Ok(())
     }
 }
 
 impl Decoder for BitcoinCodec {
-    type Item = Message;
+    type Item = CodecEvent;
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
-        if src.is_empty() {
-            return Ok(None);
+        loop {
+            let found = self.seek_magic(src);
+            // A broken rule is reported right away, even if the next frame
+            // never comes.
+            if self.skipped > 0 && (found || self.cause.is_some()) {
+                return self.resynced().map(Some);
+            }
+            if !found {
+                return Ok(None);
+            }
+            if src.len() < HEADER_SIZE {
+                return Ok(None);
+            }
+
+            let length = u32::from_le_bytes(src[16..20].try_into()?) as usize;
+            let limit = self.context.limits.max_payload;
+            if length > limit {
+                let oversize = Violation::Oversize {
+                    what: "payload",
+                    size: length as u64,
+                    limit: limit as u64,
+                };
+                // A magic found in garbage may be garbage too, along with the
+                // length after it, so like a bad checksum that only costs the
+                // magic.
+                if self.resyncing {
+                    self.blame(oversize);
+                    self.skip(src, MAGIC_SIZE);
+                    continue;
+                }
+                // Only the header is dropped, so the error isn't returned
+                // again for the same bytes; the payload, if it ever comes,
+                // is skipped as garbage.
+                self.skip(src, HEADER_SIZE);
+                self.capture_dropped()?;
+                return Err(oversize.into());
+            }
+            if src.len() < HEADER_SIZE + length {
+                src.reserve(HEADER_SIZE + length - src.len());
+                return Ok(None);
+            }
+
+            // A corrupt frame says nothing about where the next one starts,
+            // so only its magic is dropped and the search starts over.
+            let checksum = u32::from_le_bytes(src[20..HEADER_SIZE].try_into()?);
+            let actual = src[HEADER_SIZE..HEADER_SIZE + length].sha256();
+            if actual != checksum {
+                self.blame(Violation::Checksum {
+                    expected: checksum,
+                    actual,
+                });
+                self.skip(src, MAGIC_SIZE);
+                continue;
+            }
+
+            if let Some(capture) = &mut self.capture {
+                capture.record_frame(Direction::Inbound, &src[..HEADER_SIZE + length])?;
+            }
+
+            // Frozen so payloads can keep slices of the frame without copying.
+            let mut frame = src.split_to(HEADER_SIZE + length).freeze();
+            self.resyncing = false;
+            match Message::decode_verified(&mut frame, &self.context) {
+                Ok(msg) => return Ok(Some(CodecEvent::Message(msg))),
+                // Messages we don't understand yet are skipped silently.
+                Err(Error::Command(_)) => continue,
+                // Errors the peer is to blame for are for the caller to judge,
+                // see `Error::misbehavior`.
+                Err(e) => return Err(e),
+            }
         }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if let Some(event) = self.decode(src)? {
+            return Ok(Some(event));
+        }
+        // What's left can't be the start of a frame any more.
+        self.skip(src, src.len());
+        if self.skipped > 0 {
+            return self.resynced().map(Some);
+        }
+        Ok(None)
+    }
+}
 
-        let Ok(msg) = Message::decode(src) else {
-            println!("Failed to decode bytes: {:?}", src);
-            return Ok(None);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::{Command, Network, Payload};
+    use pretty_assertions::assert_eq;
+
+    fn ping(nonce: u64) -> Message {
+        let magic = Context::default().network.magic();
+        Message::new(magic, Command::Ping, Payload::Ping(nonce)).unwrap()
+    }
+
+    #[test]
+    fn resyncs_after_garbage_and_corrupt_frames() {
+        let mut codec = BitcoinCodec::default();
+        let mut src = BytesMut::from(&b"junk"[..]);
+        codec.encode(ping(1), &mut src).unwrap();
+        let corrupt = src.len();
+        codec.encode(ping(2), &mut src).unwrap();
+        src[corrupt + HEADER_SIZE] ^= 0xff;
+        let bad_checksum = Violation::Checksum {
+            expected: u32::from_le_bytes(
+                src[corrupt + 20..corrupt + HEADER_SIZE].try_into().unwrap(),
+            ),
+            actual: src[corrupt + HEADER_SIZE..corrupt + HEADER_SIZE + 8].sha256(),
         };
-        if !src.has_remaining() {
-            src.clear();
+        codec.encode(ping(3), &mut src).unwrap();
+        src.extend_from_slice(&Context::default().network.magic().to_le_bytes()[..2]);
+
+        let mut events = vec![];
+        while let Some(event) = codec.decode(&mut src).unwrap() {
+            events.push(event);
         }
+        // The partial magic at the end might still become a frame.
+        assert_eq!(src.len(), 2);
+        events.extend(codec.decode_eof(&mut src).unwrap());
+        let magic = Context::default().network.magic();
+        assert_eq!(
+            events,
+            vec![
+                CodecEvent::Resynced {
+                    skipped: 4,
+                    cause: Some(Violation::WrongMagic {
+                        expected: magic,
+                        actual: u32::from_le_bytes(*b"junk"),
+                    }),
+                },
+                CodecEvent::Message(ping(1)),
+                CodecEvent::Resynced {
+                    skipped: HEADER_SIZE + 8,
+                    cause: Some(bad_checksum),
+                },
+                CodecEvent::Message(ping(3)),
+                CodecEvent::Resynced {
+                    skipped: 2,
+                    cause: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn resyncs_past_an_oversize_length_in_garbage() {
+        let mut codec = BitcoinCodec::default();
+        let magic = Context::default().network.magic();
+        let mut src = BytesMut::from(&b"junk"[..]);
+        src.extend_from_slice(&magic.to_le_bytes());
+        src.extend_from_slice(&[0; 12]);
+        src.extend_from_slice(&u32::MAX.to_le_bytes());
+        src.extend_from_slice(&[0; 4]);
+        codec.encode(ping(1), &mut src).unwrap();
+
+        let mut events = vec![];
+        while let Some(event) = codec.decode(&mut src).unwrap() {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![
+                CodecEvent::Resynced {
+                    skipped: 4,
+                    cause: Some(Violation::WrongMagic {
+                        expected: magic,
+                        actual: u32::from_le_bytes(*b"junk"),
+                    }),
+                },
+                CodecEvent::Resynced {
+                    skipped: HEADER_SIZE,
+                    cause: Some(Violation::Oversize {
+                        what: "payload",
+                        size: u32::MAX as u64,
+                        limit: Context::default().limits.max_payload as u64,
+                    }),
+                },
+                CodecEvent::Message(ping(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_an_oversize_header() {
+        let mut codec = BitcoinCodec::default();
+        let magic = Context::default().network.magic();
+        let mut src = BytesMut::from(&magic.to_le_bytes()[..]);
+        src.extend_from_slice(b"block\0\0\0\0\0\0\0");
+        src.extend_from_slice(&u32::MAX.to_le_bytes());
+        src.extend_from_slice(&[0; 4]);
+        src.extend_from_slice(b"payload");
+        codec.encode(ping(1), &mut src).unwrap();
+
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(Error::Protocol(Violation::Oversize { .. }))
+        ));
+        // The rest is garbage, not another reason to fail.
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(CodecEvent::Resynced {
+                skipped: HEADER_SIZE + 7,
+                cause: None,
+            })
+        );
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(CodecEvent::Message(ping(1)))
+        );
+    }
+
+    #[test]
+    fn resyncs_on_wrong_magic() {
+        let mut codec = BitcoinCodec::default();
+        let mut src = BytesMut::new();
+        let testnet = Message::new(Network::Testnet.magic(), Command::Ping, Payload::Ping(0));
+        codec.encode(testnet.unwrap(), &mut src).unwrap();
+        codec.encode(ping(1), &mut src).unwrap();
 
-        Ok(Some(msg))
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(CodecEvent::Resynced {
+                skipped: HEADER_SIZE + 8,
+                cause: Some(Violation::WrongMagic {
+                    expected: Network::Mainnet.magic(),
+                    actual: Network::Testnet.magic(),
+                }),
+            })
+        );
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(CodecEvent::Message(ping(1)))
+        );
     }
 }
@@ -0,0 +1,54 @@
+use crate::bitcoin::headers::MAX_HEADERS;
+use crate::bitcoin::peer::PROTOCOL_VERSION;
+use crate::bitcoin::Network;
+
+/// Negotiated connection state the wire format depends on.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Context {
+    /// Protocol version both sides speak, the lower of the two versions
+    /// exchanged during the handshake.
+    pub version: i32,
+    pub network: Network,
+    /// Whether transactions carry witness data (BIP144).
+    pub witness: bool,
+    pub limits: Limits,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            network: Network::default(),
+            witness: true,
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// Caps on sizes announced by length prefixes, checked before anything is
+/// allocated for them. Defaults are Bitcoin Core's.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Limits {
+    /// Largest message payload.
+    pub max_payload: usize,
+    /// Longest user agent in a `version`.
+    pub max_user_agent: usize,
+    /// Most entries in an `inv`, `getdata` or `notfound`.
+    pub max_inventory: usize,
+    /// Most entries in an `addr`.
+    pub max_addresses: usize,
+    /// Most headers in a `headers`.
+    pub max_headers: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_payload: 4_000_000,
+            max_user_agent: 256,
+            max_inventory: 50_000,
+            max_addresses: 1000,
+            max_headers: MAX_HEADERS,
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use crate::bitcoin::peer::{Peer, Transport};
+use crate::bitcoin::{Address, Payload, VersionMessage};
+use futures::stream::FuturesUnordered;
+use futures::{Future, Stream, StreamExt};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, timeout_at, Instant};
+
+#[derive(Debug, Clone)]
+pub struct CrawlOptions {
+    /// Most nodes being visited at any time.
+    pub concurrency: usize,
+    /// Stop queueing new nodes once this many have been visited.
+    pub max_nodes: usize,
+    /// Time allowed to connect and handshake.
+    pub connect_timeout: Duration,
+    /// Time a node gets to answer `getaddr` and our ping after the handshake.
+    pub addr_timeout: Duration,
+}
+
+impl Default for CrawlOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 64,
+            max_nodes: usize::MAX,
+            connect_timeout: Duration::from_secs(10),
+            addr_timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+/// What we learned about one node.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NodeRecord {
+    pub addr: SocketAddr,
+    /// The node's version message, if the handshake succeeded.
+    pub version: Option<VersionMessage>,
+    /// Ping round trip after the handshake.
+    pub latency: Option<Duration>,
+    /// When we reached the node, or else the newest time other nodes
+    /// advertised it with.
+    pub last_seen: Option<SystemTime>,
+    /// Addresses the node sent us.
+    pub addresses: usize,
+    /// Why the node couldn't be reached, if it couldn't.
+    pub error: Option<String>,
+}
+
+impl NodeRecord {
+    pub fn is_reachable(&self) -> bool {
+        self.version.is_some()
+    }
+}
+
+/// Which nodes to visit next, independent of how they're visited. Every
+/// address is visited once, seeds first, then in the order they're learned.
+#[derive(Debug, Default)]
+pub struct Crawler {
+    queue: VecDeque<SocketAddr>,
+    seen: HashSet<SocketAddr>,
+    /// Newest time each address was advertised with.
+    advertised: HashMap<SocketAddr, SystemTime>,
+    visited: usize,
+    max_nodes: usize,
+}
+
+impl Crawler {
+    pub fn new(seeds: impl IntoIterator<Item = SocketAddr>, max_nodes: usize) -> Self {
+        let mut crawler = Self {
+            max_nodes,
+            ..Default::default()
+        };
+        for seed in seeds {
+            crawler.enqueue(seed);
+        }
+        crawler
+    }
+
+    fn enqueue(&mut self, addr: SocketAddr) {
+        if self.seen.insert(addr) {
+            self.queue.push_back(addr);
+        }
+    }
+
+    /// The next node to visit, if any are left and the limit isn't reached.
+    pub fn next_target(&mut self) -> Option<SocketAddr> {
+        if self.visited >= self.max_nodes {
+            return None;
+        }
+        let addr = self.queue.pop_front()?;
+        self.visited += 1;
+        Some(addr)
+    }
+
+    /// Queues the addresses a node sent us. Ones no one could connect to,
+    /// like unspecified IPs or port 0, are ignored.
+    pub fn learn(&mut self, addresses: &[Address<u32>]) {
+        for address in addresses {
+            let addr = SocketAddr::new(address.ip, address.port.clone().into());
+            if addr.ip().is_unspecified() || addr.port() == 0 {
+                continue;
+            }
+            let time = UNIX_EPOCH + Duration::from_secs(address.time.into());
+            let newest = self.advertised.entry(addr).or_insert(time);
+            *newest = (*newest).max(time);
+            self.enqueue(addr);
+        }
+    }
+
+    /// Newest time `addr` was advertised with by the nodes visited so far.
+    pub fn advertised(&self, addr: &SocketAddr) -> Option<SystemTime> {
+        self.advertised.get(addr).copied()
+    }
+
+    pub fn visited(&self) -> usize {
+        self.visited
+    }
+}
+
+/// Crawls the network from `seeds`: every node is handshaked with, asked
+/// for addresses and pinged, and the addresses it returns are crawled in
+/// turn. `connect` opens and handshakes a connection, so proxies are up to
+/// the caller. Yields a record per visited node as soon as it's done.
+pub fn crawl<T, E, F, Fut>(
+    seeds: Vec<SocketAddr>,
+    options: CrawlOptions,
+    connect: F,
+) -> impl Stream<Item = NodeRecord>
+where
+    T: Transport,
+    E: Display + Send + 'static,
+    F: Fn(SocketAddr) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Peer<T>, E>> + Send + 'static,
+{
+    let (out_tx, out_rx) = mpsc::channel(options.concurrency.max(1));
+    tokio::spawn(coordinate(seeds, options, Arc::new(connect), out_tx));
+    futures::stream::unfold(out_rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    })
+}
+
+async fn coordinate<T, E, F, Fut>(
+    seeds: Vec<SocketAddr>,
+    options: CrawlOptions,
+    connect: Arc<F>,
+    out: mpsc::Sender<NodeRecord>,
+) where
+    T: Transport,
+    E: Display + Send + 'static,
+    F: Fn(SocketAddr) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Peer<T>, E>> + Send + 'static,
+{
+    let mut crawler = Crawler::new(seeds, options.max_nodes);
+    let mut visits = FuturesUnordered::new();
+    loop {
+        while visits.len() < options.concurrency.max(1) {
+            let Some(addr) = crawler.next_target() else {
+                break;
+            };
+            let connect = connect.clone();
+            let options = options.clone();
+            visits.push(tokio::spawn(async move {
+                visit(addr, connect(addr), &options).await
+            }));
+        }
+        let Some(result) = visits.next().await else {
+            return;
+        };
+        // A panicking visit loses its record, but not the crawl.
+        let Ok((mut record, addresses)) = result else {
+            continue;
+        };
+        crawler.learn(&addresses);
+        if record.last_seen.is_none() {
+            record.last_seen = crawler.advertised(&record.addr);
+        }
+        if out.send(record).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Handshakes with a node, then asks for its addresses and pings it until
+/// it answers both or runs out of time.
+async fn visit<T: Transport, E: Display>(
+    addr: SocketAddr,
+    connecting: impl Future<Output = Result<Peer<T>, E>>,
+    options: &CrawlOptions,
+) -> (NodeRecord, Vec<Address<u32>>) {
+    let mut record = NodeRecord {
+        addr,
+        version: None,
+        latency: None,
+        last_seen: None,
+        addresses: 0,
+        error: None,
+    };
+    let mut peer = match timeout(options.connect_timeout, connecting).await {
+        Ok(Ok(peer)) => peer,
+        Ok(Err(e)) => {
+            record.error = Some(e.to_string());
+            return (record, vec![]);
+        }
+        Err(_) => {
+            record.error = Some("timed out".to_string());
+            return (record, vec![]);
+        }
+    };
+    record.version = Some(peer.version().clone());
+    record.last_seen = Some(SystemTime::now());
+
+    let mut addresses = Vec::new();
+    let nonce = rand::random();
+    let pinged = Instant::now();
+    if peer.send(Payload::GetAddr).await.is_err() || peer.send(Payload::Ping(nonce)).await.is_err()
+    {
+        return (record, addresses);
+    }
+    // Nodes announce themselves in a single-entry addr, so only a longer
+    // one counts as the answer to getaddr.
+    let mut answered = false;
+    let deadline = pinged + options.addr_timeout;
+    while !answered || record.latency.is_none() {
+        match timeout_at(deadline, peer.recv()).await {
+            Ok(Ok(Payload::Pong(pong))) if pong == nonce => {
+                record.latency = Some(pinged.elapsed());
+            }
+            Ok(Ok(Payload::Addr(received))) => {
+                answered |= received.len() > 1;
+                addresses.extend(received);
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(_)) | Err(_) => break,
+        }
+    }
+    record.addresses = addresses.len();
+    (record, addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::mock::{MockConfig, MockNode};
+    use crate::bitcoin::peer::PeerConfig;
+    use crate::bitcoin::{Error, Network, ServiceFlags};
+    use pretty_assertions::assert_eq;
+    use tokio::io::DuplexStream;
+
+    fn address(addr: &str, time: u32) -> Address<u32> {
+        let addr: SocketAddr = addr.parse().unwrap();
+        Address {
+            time,
+            services: ServiceFlags::NETWORK,
+            ip: addr.ip(),
+            port: addr.port().into(),
+        }
+    }
+
+    #[test]
+    fn visits_each_address_once() {
+        let seed: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        let mut crawler = Crawler::new([seed, seed], 3);
+        assert_eq!(crawler.next_target(), Some(seed));
+        assert_eq!(crawler.next_target(), None);
+
+        crawler.learn(&[
+            address("10.0.0.1:8333", 5),
+            address("10.0.0.2:8333", 5),
+            address("10.0.0.2:8333", 9),
+            address("0.0.0.0:8333", 5),
+            address("10.0.0.3:0", 5),
+            address("10.0.0.3:8333", 5),
+            address("10.0.0.4:8333", 5),
+        ]);
+        let second: SocketAddr = "10.0.0.2:8333".parse().unwrap();
+        assert_eq!(
+            crawler.advertised(&second),
+            Some(UNIX_EPOCH + Duration::from_secs(9))
+        );
+        assert_eq!(crawler.next_target(), Some(second));
+        assert_eq!(crawler.next_target(), "10.0.0.3:8333".parse().ok());
+        // The limit of three is reached.
+        assert_eq!(crawler.next_target(), None);
+        assert_eq!(crawler.visited(), 3);
+    }
+
+    #[tokio::test]
+    async fn crawls_through_addr() {
+        // The seed knows two nodes, one of which knows a third which is down.
+        let graph: HashMap<SocketAddr, Vec<Address<u32>>> = [
+            (
+                "10.0.0.1:18444",
+                vec![
+                    address("10.0.0.2:18444", 100),
+                    address("10.0.0.3:18444", 100),
+                ],
+            ),
+            ("10.0.0.2:18444", vec![]),
+            (
+                "10.0.0.3:18444",
+                vec![
+                    address("10.0.0.1:18444", 100),
+                    address("10.0.0.4:18444", 200),
+                ],
+            ),
+        ]
+        .into_iter()
+        .map(|(addr, addresses)| (addr.parse().unwrap(), addresses))
+        .collect();
+        let graph = Arc::new(graph);
+
+        let connect = move |addr: SocketAddr| {
+            let graph = graph.clone();
+            async move {
+                let addresses = graph.get(&addr).ok_or(Error::ConnectionClosed)?.clone();
+                let (ours, theirs) = tokio::io::duplex(1 << 16);
+                let node = MockNode::new(MockConfig {
+                    addresses,
+                    ..Default::default()
+                });
+                tokio::spawn(async move { node.serve(theirs).await });
+                let config = PeerConfig {
+                    network: Network::Regtest,
+                    ..Default::default()
+                };
+                Peer::<DuplexStream>::handshake(ours, addr, &config).await
+            }
+        };
+        let options = CrawlOptions {
+            addr_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let seed = "10.0.0.1:18444".parse().unwrap();
+        let mut records = crawl(vec![seed], options, connect)
+            .collect::<Vec<_>>()
+            .await;
+        records.sort_by_key(|record| record.addr);
+
+        let addrs = records.iter().map(|record| record.addr.to_string());
+        assert_eq!(
+            addrs.collect::<Vec<_>>(),
+            vec![
+                "10.0.0.1:18444",
+                "10.0.0.2:18444",
+                "10.0.0.3:18444",
+                "10.0.0.4:18444"
+            ]
+        );
+        assert!(records[..3].iter().all(NodeRecord::is_reachable));
+        assert!(records[..3].iter().all(|record| record.latency.is_some()));
+        assert_eq!(records[0].addresses, 2);
+        let version = records[2].version.as_ref().unwrap();
+        assert_eq!(version.user_agent, "/mock:0.1.0/".into());
+
+        let unreachable = &records[3];
+        assert!(!unreachable.is_reachable());
+        assert!(unreachable.error.is_some());
+        assert_eq!(
+            unreachable.last_seen,
+            Some(UNIX_EPOCH + Duration::from_secs(200))
+        );
+    }
+}
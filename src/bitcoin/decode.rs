@@ -1,21 +1,30 @@
-use crate::bitcoin::Result;
+use crate::bitcoin::{BigEndian, Context, Error, Result};
 use bytes::Buf;
 
 pub trait Decode
 where
     Self: Sized,
 {
-    fn decode(buffer: &mut impl Buf) -> Result<Self>;
+    fn decode_with(buffer: &mut impl Buf, context: &Context) -> Result<Self>;
+
+    /// Decodes with the default context: our own protocol version, witness
+    /// data expected.
+    fn decode(buffer: &mut impl Buf) -> Result<Self> {
+        Self::decode_with(buffer, &Context::default())
+    }
 }
 
 impl Decode for () {
-    fn decode(_buffer: &mut impl Buf) -> Result<Self> {
+    fn decode_with(_buffer: &mut impl Buf, _context: &Context) -> Result<Self> {
         Ok(())
     }
 }
 
 impl Decode for bool {
-    fn decode(buffer: &mut impl Buf) -> Result<Self> {
+    fn decode_with(buffer: &mut impl Buf, _context: &Context) -> Result<Self> {
+        if buffer.remaining() < 1 {
+            return Err(Error::NotEnoughBytes("bool"));
+        }
         Ok(buffer.get_u8() != 0)
     }
 }
@@ -23,9 +32,9 @@ impl Decode for bool {
 macro_rules! make_decoder {
     ($t: ty, $fn: ident, $n: tt) => {
         impl Decode for $t {
-            fn decode(buffer: &mut impl Buf) -> Result<Self> {
+            fn decode_with(buffer: &mut impl Buf, _context: &Context) -> Result<Self> {
                 if buffer.remaining() < $n {
-                    return Err(crate::bitcoin::Error::NotEnoughBytes(stringify!($t)));
+                    return Err(Error::NotEnoughBytes(stringify!($t)));
                 }
                 Ok(buffer.$fn())
             }
@@ -42,12 +51,29 @@ make_decoder!(i16, get_i16_le, 2);
 make_decoder!(i32, get_i32_le, 4);
 make_decoder!(i64, get_i64_le, 8);
 
+macro_rules! make_big_endian_decoder {
+    ($t: ty, $fn: ident, $n: tt) => {
+        impl Decode for BigEndian<$t> {
+            fn decode_with(buffer: &mut impl Buf, _context: &Context) -> Result<Self> {
+                if buffer.remaining() < $n {
+                    return Err(Error::NotEnoughBytes(stringify!($t)));
+                }
+                Ok(BigEndian(buffer.$fn()))
+            }
+        }
+    };
+}
+
+make_big_endian_decoder!(u16, get_u16, 2);
+make_big_endian_decoder!(u32, get_u32, 4);
+make_big_endian_decoder!(u64, get_u64, 8);
+
 impl Decode for std::net::IpAddr {
-    fn decode(buffer: &mut impl Buf) -> Result<Self> {
+    fn decode_with(buffer: &mut impl Buf, _context: &Context) -> Result<Self> {
         if buffer.remaining() < 16 {
-            return Err(crate::bitcoin::Error::NotEnoughBytes("IpAddr"));
+            return Err(Error::NotEnoughBytes("IpAddr"));
         }
-        let ip = TryInto::<[u8; 16]>::try_into(&buffer.copy_to_bytes(16)[..])?.into();
-        Ok(ip)
+        let ip: [u8; 16] = buffer.copy_to_bytes(16)[..].try_into()?;
+        Ok(std::net::Ipv6Addr::from(ip).to_canonical())
     }
 }
@@ -0,0 +1,371 @@
+use crate::bitcoin::peer::{Peer, Transport};
+use crate::bitcoin::{
+    BlockHash, Error, Inventory, InventoryKind, LazyBlock, Payload, Result, Violation,
+};
+use futures::Stream;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Most blocks requested or waiting to be delivered at any time.
+    pub window: usize,
+    /// Most blocks in flight from a single peer.
+    pub per_peer: usize,
+    /// A peer which hasn't delivered a requested block in this long is
+    /// disconnected and the block asked from someone else.
+    pub stall_timeout: Duration,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            window: 128,
+            per_peer: 16,
+            stall_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct InFlight {
+    height: u32,
+    peer: SocketAddr,
+    requested_at: Instant,
+}
+
+/// Schedules block requests across peers and hands blocks back in height
+/// order, independent of how the peers are driven.
+#[derive(Debug)]
+pub struct BlockDownloader {
+    options: DownloadOptions,
+    /// Blocks not yet requested, sorted by height.
+    queue: VecDeque<(u32, BlockHash)>,
+    in_flight: HashMap<BlockHash, InFlight>,
+    ready: BTreeMap<u32, LazyBlock>,
+    /// Heights still to be delivered, in order.
+    pending: VecDeque<u32>,
+}
+
+impl BlockDownloader {
+    pub fn new(mut blocks: Vec<(u32, BlockHash)>, options: DownloadOptions) -> Self {
+        blocks.sort_by_key(|(height, _)| *height);
+        blocks.dedup_by_key(|(height, _)| *height);
+        Self {
+            options,
+            pending: blocks.iter().map(|(height, _)| *height).collect(),
+            queue: blocks.into(),
+            in_flight: HashMap::new(),
+            ready: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Returns a `getdata` for as many blocks as `peer` may have in flight,
+    /// limited by the download window.
+    pub fn request(&mut self, peer: SocketAddr, now: Instant) -> Option<Payload> {
+        let from_peer = self.in_flight.values().filter(|f| f.peer == peer).count();
+        let buffered = self.in_flight.len() + self.ready.len();
+        let count = self
+            .options
+            .per_peer
+            .saturating_sub(from_peer)
+            .min(self.options.window.saturating_sub(buffered))
+            .min(self.queue.len());
+        if count == 0 {
+            return None;
+        }
+
+        let inventory = self
+            .queue
+            .drain(..count)
+            .map(|(height, hash)| {
+                self.in_flight.insert(
+                    hash,
+                    InFlight {
+                        height,
+                        peer,
+                        requested_at: now,
+                    },
+                );
+                Inventory {
+                    kind: InventoryKind::WitnessBlock,
+                    hash,
+                }
+            })
+            .collect();
+        Some(Payload::GetData(inventory))
+    }
+
+    /// Accepts a block downloaded from `peer`. Fails if it's not one we're
+    /// waiting for from that peer, or if it doesn't match its header.
+    pub fn receive(&mut self, peer: SocketAddr, block: LazyBlock) -> Result<()> {
+        let hash = block.block_hash();
+        let in_flight = match self.in_flight.get(&hash) {
+            Some(in_flight) if in_flight.peer == peer => in_flight,
+            _ => return Err(Violation::Unsolicited("block").into()),
+        };
+        block.check_merkle_root()?;
+        self.ready.insert(in_flight.height, block);
+        self.in_flight.remove(&hash);
+        Ok(())
+    }
+
+    /// Puts requests older than the stall timeout back in the queue and
+    /// returns the peers responsible.
+    pub fn check_stalls(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let mut stalled = self
+            .in_flight
+            .values()
+            .filter(|f| now.duration_since(f.requested_at) >= self.options.stall_timeout)
+            .map(|f| f.peer)
+            .collect::<Vec<_>>();
+        stalled.sort();
+        stalled.dedup();
+        for peer in &stalled {
+            self.peer_disconnected(*peer);
+        }
+        stalled
+    }
+
+    /// Puts everything requested from `peer` back in the queue.
+    pub fn peer_disconnected(&mut self, peer: SocketAddr) {
+        let hashes = self
+            .in_flight
+            .iter()
+            .filter(|(_, f)| f.peer == peer)
+            .map(|(hash, _)| *hash)
+            .collect::<Vec<_>>();
+        for hash in hashes {
+            let height = self.in_flight.remove(&hash).unwrap().height;
+            let index = self.queue.partition_point(|(h, _)| *h < height);
+            self.queue.insert(index, (height, hash));
+        }
+    }
+
+    /// Next block in height order, once it has arrived.
+    pub fn pop_ready(&mut self) -> Option<(u32, LazyBlock)> {
+        let height = *self.pending.front()?;
+        let block = self.ready.remove(&height)?;
+        self.pending.pop_front();
+        Some((height, block))
+    }
+}
+
+/// Downloads `blocks` from `peers` in parallel and yields them in height
+/// order, still serialized as received. The stream ends early with an error
+/// if every peer goes away.
+pub fn download<T: Transport>(
+    peers: Vec<Peer<T>>,
+    blocks: Vec<(u32, BlockHash)>,
+    options: DownloadOptions,
+) -> impl Stream<Item = Result<(u32, LazyBlock)>> {
+    let (out_tx, out_rx) = mpsc::channel(options.window.max(1));
+    tokio::spawn(coordinate(peers, blocks, options, out_tx));
+    futures::stream::unfold(out_rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    })
+}
+
+type PeerEvent = (SocketAddr, Result<LazyBlock>);
+
+async fn coordinate<T: Transport>(
+    peers: Vec<Peer<T>>,
+    blocks: Vec<(u32, BlockHash)>,
+    options: DownloadOptions,
+    out: mpsc::Sender<Result<(u32, LazyBlock)>>,
+) {
+    let (event_tx, mut events) = mpsc::unbounded_channel();
+    let mut senders = HashMap::new();
+    for peer in peers {
+        let (command_tx, commands) = mpsc::unbounded_channel();
+        senders.insert(peer.addr(), command_tx);
+        tokio::spawn(drive_peer(peer, commands, event_tx.clone()));
+    }
+    drop(event_tx);
+
+    let mut downloader = BlockDownloader::new(blocks, options);
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+
+    while !downloader.is_finished() {
+        let now = Instant::now();
+        senders.retain(|addr, sender: &mut mpsc::UnboundedSender<Payload>| {
+            match downloader.request(*addr, now) {
+                Some(getdata) => sender.send(getdata).is_ok(),
+                None => true,
+            }
+        });
+        if senders.is_empty() {
+            let _ = out.send(Err(Error::ConnectionClosed)).await;
+            return;
+        }
+
+        tokio::select! {
+            event = events.recv() => match event {
+                // Peers already dropped may still have had blocks on the way.
+                Some((addr, _)) if !senders.contains_key(&addr) => {}
+                Some((addr, Ok(block))) => {
+                    if downloader.receive(addr, block).is_err() {
+                        senders.remove(&addr);
+                        downloader.peer_disconnected(addr);
+                    }
+                }
+                Some((addr, Err(_))) => {
+                    senders.remove(&addr);
+                    downloader.peer_disconnected(addr);
+                }
+                None => senders.clear(),
+            },
+            _ = ticker.tick() => {
+                for addr in downloader.check_stalls(Instant::now()) {
+                    senders.remove(&addr);
+                }
+            }
+        }
+
+        while let Some(item) = downloader.pop_ready() {
+            if out.send(Ok(item)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Forwards getdata requests to the peer and blocks back to the coordinator,
+/// until either side goes away.
+async fn drive_peer<T: Transport>(
+    mut peer: Peer<T>,
+    mut commands: mpsc::UnboundedReceiver<Payload>,
+    events: mpsc::UnboundedSender<PeerEvent>,
+) {
+    let addr = peer.addr();
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(payload) => {
+                    if let Err(e) = peer.send(payload).await {
+                        let _ = events.send((addr, Err(e)));
+                        return;
+                    }
+                }
+                None => return,
+            },
+            payload = peer.recv() => match payload {
+                Ok(Payload::Block(block)) => {
+                    if events.send((addr, Ok(block))).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    let _ = events.send((addr, Err(e)));
+                    return;
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::{Block, BlockHeader, Network, Transaction, TxIn, TxOut};
+    use pretty_assertions::assert_eq;
+
+    fn block(height: u32) -> Block {
+        let transactions = vec![Transaction {
+            version: 1,
+            inputs: vec![TxIn::default()],
+            outputs: vec![TxOut {
+                value: height as i64,
+                script_pubkey: Default::default(),
+            }],
+            lock_time: 0,
+        }];
+        let header = BlockHeader {
+            merkle_root: transactions[0].txid(),
+            time: height,
+            ..BlockHeader::genesis(Network::Regtest)
+        };
+        Block {
+            header,
+            transactions,
+        }
+    }
+
+    #[test]
+    fn delivers_in_order_and_rerequests_stalls() {
+        let blocks = (0..4)
+            .map(|height| LazyBlock::from(&block(height)))
+            .collect::<Vec<_>>();
+        let wanted = blocks
+            .iter()
+            .enumerate()
+            .map(|(height, block)| (height as u32, block.block_hash()))
+            .collect();
+        let a: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:8333".parse().unwrap();
+        let now = Instant::now();
+
+        let mut downloader = BlockDownloader::new(
+            wanted,
+            DownloadOptions {
+                window: 3,
+                per_peer: 2,
+                stall_timeout: Duration::from_secs(5),
+            },
+        );
+        let Some(Payload::GetData(first)) = downloader.request(a, now) else {
+            panic!("expected getdata");
+        };
+        assert_eq!(first.len(), 2);
+        let Some(Payload::GetData(second)) = downloader.request(b, now) else {
+            panic!("expected getdata");
+        };
+        // Window of three leaves room for only one more.
+        assert_eq!(second.len(), 1);
+
+        downloader.receive(a, blocks[1].clone()).unwrap();
+        downloader.receive(b, blocks[2].clone()).unwrap();
+        assert!(matches!(
+            downloader.receive(b, blocks[1].clone()),
+            Err(Error::Protocol(Violation::Unsolicited("block")))
+        ));
+        assert_eq!(downloader.pop_ready(), None);
+
+        // Peer a never delivers block 0.
+        assert_eq!(
+            downloader.check_stalls(now + Duration::from_secs(5)),
+            vec![a]
+        );
+        let Some(Payload::GetData(retry)) = downloader.request(b, now) else {
+            panic!("expected getdata");
+        };
+        assert_eq!(retry[0].hash, blocks[0].block_hash());
+
+        downloader.receive(b, blocks[0].clone()).unwrap();
+        assert_eq!(downloader.pop_ready().map(|(h, _)| h), Some(0));
+        assert_eq!(downloader.pop_ready().map(|(h, _)| h), Some(1));
+        assert_eq!(downloader.pop_ready().map(|(h, _)| h), Some(2));
+        assert_eq!(downloader.pop_ready(), None);
+        assert!(!downloader.is_finished());
+    }
+
+    #[test]
+    fn rejects_bad_merkle_root() {
+        let mut bad = block(0);
+        let wanted = vec![(0, bad.block_hash())];
+        bad.transactions[0].lock_time = 1;
+
+        let mut downloader = BlockDownloader::new(wanted, DownloadOptions::default());
+        let peer = "10.0.0.1:8333".parse().unwrap();
+        downloader.request(peer, Instant::now());
+        assert!(downloader.receive(peer, LazyBlock::from(&bad)).is_err());
+    }
+}
@@ -1,14 +1,20 @@
-use crate::bitcoin::{Error, Result};
+use crate::bitcoin::{Context, Error, Result};
 use bytes::BufMut;
 
 pub trait Encode {
-    fn encode(&self, buffer: &mut impl BufMut) -> Result<usize>;
+    fn encode_with(&self, buffer: &mut impl BufMut, context: &Context) -> Result<usize>;
+
+    /// Encodes with the default context: our own protocol version, witness
+    /// data included.
+    fn encode(&self, buffer: &mut impl BufMut) -> Result<usize> {
+        self.encode_with(buffer, &Context::default())
+    }
 }
 
 macro_rules! make_encoder {
     ($t: ty, $fn: ident, $n: tt) => {
         impl Encode for $t {
-            fn encode(&self, buffer: &mut impl BufMut) -> Result<usize> {
+            fn encode_with(&self, buffer: &mut impl BufMut, _context: &Context) -> Result<usize> {
                 if buffer.remaining_mut() < $n {
                     return Err(Error::NotEnoughSpace(stringify!($t)));
                 }
@@ -28,8 +34,31 @@ make_encoder!(i16, put_i16_le, 2);
 make_encoder!(i32, put_i32_le, 4);
 make_encoder!(i64, put_i64_le, 8);
 
+/// Integer in network byte order, for the few fields (like ports) which
+/// aren't little-endian.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct BigEndian<T>(pub T);
+
+macro_rules! make_big_endian_encoder {
+    ($t: ty, $fn: ident, $n: tt) => {
+        impl Encode for BigEndian<$t> {
+            fn encode_with(&self, buffer: &mut impl BufMut, _context: &Context) -> Result<usize> {
+                if buffer.remaining_mut() < $n {
+                    return Err(Error::NotEnoughSpace(stringify!($t)));
+                }
+                buffer.$fn(self.0);
+                Ok($n)
+            }
+        }
+    };
+}
+
+make_big_endian_encoder!(u16, put_u16, 2);
+make_big_endian_encoder!(u32, put_u32, 4);
+make_big_endian_encoder!(u64, put_u64, 8);
+
 impl Encode for bool {
-    fn encode(&self, buffer: &mut impl BufMut) -> Result<usize> {
+    fn encode_with(&self, buffer: &mut impl BufMut, _context: &Context) -> Result<usize> {
         if buffer.remaining_mut() < 1 {
             return Err(Error::NotEnoughSpace("bool"));
         }
@@ -39,19 +68,20 @@ impl Encode for bool {
 }
 
 impl Encode for () {
-    fn encode(&self, _buffer: &mut impl BufMut) -> Result<usize> {
+    fn encode_with(&self, _buffer: &mut impl BufMut, _context: &Context) -> Result<usize> {
         Ok(0)
     }
 }
 
 impl Encode for std::net::IpAddr {
-    fn encode(&self, buffer: &mut impl BufMut) -> Result<usize> {
+    fn encode_with(&self, buffer: &mut impl BufMut, _context: &Context) -> Result<usize> {
         if buffer.remaining_mut() < 16 {
             return Err(Error::NotEnoughSpace("IpAddr"));
         }
         use std::net::IpAddr::*;
         match self {
-            V4(ip) => buffer.put_slice(&[[0; 4], [0; 4], [0; 4], ip.octets()].concat()),
+            // IPv4 travels as an IPv4-mapped IPv6 address.
+            V4(ip) => buffer.put_slice(&ip.to_ipv6_mapped().octets()),
             V6(ip) => buffer.put_slice(&ip.octets()),
         }
         Ok(16)
@@ -10,8 +10,161 @@ pub enum Error {
     TryFromSlice(#[from] std::array::TryFromSliceError),
     #[error("command error: {0}")]
     Command(String),
+    /// Input ended early. Decoding may succeed once more bytes arrive.
     #[error("not enough bytes to decode: {0}")]
     NotEnoughBytes(&'static str),
     #[error("not enough space to encode: {0}")]
     NotEnoughSpace(&'static str),
+    /// Complete input which can't be decoded. `field` is the path to the
+    /// offending field, like `version.user_agent`, and `offset` the position
+    /// in the message where decoding stopped, when known.
+    #[error("malformed {}: {reason}", location(.field, .offset))]
+    Malformed {
+        field: String,
+        offset: Option<usize>,
+        reason: String,
+    },
+    #[error("protocol violation: {0}")]
+    Protocol(#[from] Violation),
+    #[error("parse error: {0}")]
+    Parse(String),
+    #[error("handshake error: {0}")]
+    Handshake(String),
+    #[error("invalid block header: {0}")]
+    InvalidHeader(String),
+    #[error("invalid block: {0}")]
+    InvalidBlock(String),
+    #[error("connection closed")]
+    ConnectionClosed,
+    /// The remote node sent back the nonce of one of our own version
+    /// messages: we connected to ourselves.
+    #[error("connected to ourselves")]
+    SelfConnection,
+}
+
+/// Rules a peer broke, independent of what the payload contained.
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
+pub enum Violation {
+    #[error("checksum mismatch: header says {expected:#010x}, payload hashes to {actual:#010x}")]
+    Checksum { expected: u32, actual: u32 },
+    #[error("wrong network magic {actual:#010x}, expected {expected:#010x}")]
+    WrongMagic { expected: u32, actual: u32 },
+    #[error("{what} of {size} exceeds the limit of {limit}")]
+    Oversize {
+        what: &'static str,
+        size: u64,
+        limit: u64,
+    },
+    /// Data we never asked for, like a block that wasn't requested.
+    #[error("unsolicited {0}")]
+    Unsolicited(&'static str),
+}
+
+/// Broad classes of errors, for deciding how to react to one.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ErrorKind {
+    /// Retry once more input is available.
+    Incomplete,
+    /// The peer sent something that can't be decoded.
+    Malformed,
+    /// The peer broke the protocol: bad framing, oversized or invalid data.
+    Protocol,
+    /// The transport failed or the connection is gone.
+    Io,
+    /// Nothing the peer can be blamed for.
+    Local,
+}
+
+impl Error {
+    pub fn malformed(reason: impl Into<String>) -> Self {
+        Self::Malformed {
+            field: String::new(),
+            offset: None,
+            reason: reason.into(),
+        }
+    }
+
+    /// Records that the error happened while decoding `field`. Running out of
+    /// bytes inside a field means the enclosing input was complete but too
+    /// short, so it becomes a malformed-input error.
+    pub fn in_field(self, name: &str) -> Self {
+        match self {
+            Self::NotEnoughBytes(what) => Self::Malformed {
+                field: name.to_string(),
+                offset: None,
+                reason: format!("not enough bytes for {}", what),
+            },
+            Self::Malformed {
+                field,
+                offset,
+                reason,
+            } => Self::Malformed {
+                field: if field.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{}.{}", name, field)
+                },
+                offset,
+                reason,
+            },
+            e => e,
+        }
+    }
+
+    /// Sets the offset of a malformed-input error which doesn't have one yet.
+    pub fn at_offset(self, position: usize) -> Self {
+        match self {
+            Self::Malformed {
+                field,
+                offset,
+                reason,
+            } => Self::Malformed {
+                field,
+                offset: offset.or(Some(position)),
+                reason,
+            },
+            e => e,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::NotEnoughBytes(_) => ErrorKind::Incomplete,
+            Self::Malformed { .. } => ErrorKind::Malformed,
+            Self::Protocol(_)
+            | Self::Handshake(_)
+            | Self::InvalidHeader(_)
+            | Self::InvalidBlock(_) => ErrorKind::Protocol,
+            Self::IO(_) | Self::ConnectionClosed => ErrorKind::Io,
+            Self::Utf8(_)
+            | Self::TryFromSlice(_)
+            | Self::Command(_)
+            | Self::NotEnoughSpace(_)
+            | Self::Parse(_)
+            | Self::SelfConnection => ErrorKind::Local,
+        }
+    }
+
+    /// Misbehavior points the peer which caused this error earns, on the
+    /// scale Bitcoin Core uses where 100 gets a peer disconnected.
+    pub fn misbehavior(&self) -> u32 {
+        match self {
+            Self::Malformed { .. } => 20,
+            Self::Protocol(Violation::Checksum { .. }) => 10,
+            Self::Protocol(Violation::WrongMagic { .. }) => 100,
+            Self::Protocol(Violation::Oversize { .. }) => 20,
+            Self::Protocol(Violation::Unsolicited(_)) => 10,
+            Self::Handshake(_) => 10,
+            Self::InvalidHeader(_) | Self::InvalidBlock(_) => 100,
+            _ => 0,
+        }
+    }
+}
+
+fn location(field: &str, offset: &Option<usize>) -> String {
+    let field = if field.is_empty() { "input" } else { field };
+    match offset {
+        Some(offset) => format!("{} at byte {}", field, offset),
+        None => field.to_string(),
+    }
 }
@@ -0,0 +1,82 @@
+use crate::bitcoin::{Context, Decode, Encode, Error, Result};
+use bytes::{Buf, BufMut};
+use std::fmt;
+
+/// Double SHA-256 digest, stored in internal byte order and displayed reversed
+/// as is conventional for txids and block hashes.
+#[derive(Clone, Copy, Default, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct Hash256([u8; 32]);
+
+pub type Txid = Hash256;
+pub type Wtxid = Hash256;
+pub type BlockHash = Hash256;
+
+impl Hash256 {
+    pub fn hash(data: &[u8]) -> Self {
+        use sha2::{Digest, Sha256};
+        let first = Sha256::digest(data);
+        Self(Sha256::digest(first).into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for Hash256 {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl fmt::Display for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.iter().rev() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hash256({})", self)
+    }
+}
+
+impl std::str::FromStr for Hash256 {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(Error::Parse(format!("invalid hash: {:?}", s)));
+        }
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().rev().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                .map_err(|_| Error::Parse(format!("invalid hash: {:?}", s)))?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl Encode for Hash256 {
+    fn encode_with(&self, buffer: &mut impl BufMut, _context: &Context) -> Result<usize> {
+        if buffer.remaining_mut() < 32 {
+            return Err(Error::NotEnoughSpace("hash"));
+        }
+        buffer.put_slice(&self.0);
+        Ok(32)
+    }
+}
+
+impl Decode for Hash256 {
+    fn decode_with(bytes: &mut impl Buf, _context: &Context) -> Result<Self> {
+        if bytes.remaining() < 32 {
+            return Err(Error::NotEnoughBytes("hash"));
+        }
+        let mut hash = [0; 32];
+        bytes.copy_to_slice(&mut hash);
+        Ok(Self(hash))
+    }
+}
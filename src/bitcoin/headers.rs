@@ -0,0 +1,282 @@
+use crate::bitcoin::peer::{Peer, Transport, PROTOCOL_VERSION};
+use crate::bitcoin::{
+    BlockHash, BlockHeader, Error, GetHeadersMessage, Hash256, Network, Payload, Result,
+};
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+
+/// Most headers a peer returns for a single `getheaders`.
+pub const MAX_HEADERS: usize = 2000;
+
+/// The best header chain we know of, starting at the network's genesis block.
+///
+/// Headers must connect to the current tip; reorganisations are not handled.
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    headers: Vec<BlockHeader>,
+    hashes: Vec<BlockHash>,
+    heights: HashMap<BlockHash, u32>,
+}
+
+impl HeaderChain {
+    pub fn new(network: Network) -> Self {
+        let genesis = BlockHeader::genesis(network);
+        let hash = genesis.block_hash();
+        Self {
+            headers: vec![genesis],
+            hashes: vec![hash],
+            heights: HashMap::from([(hash, 0)]),
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        (self.headers.len() - 1) as u32
+    }
+
+    pub fn tip(&self) -> BlockHash {
+        self.hashes[self.hashes.len() - 1]
+    }
+
+    pub fn hash_at(&self, height: u32) -> Option<BlockHash> {
+        self.hashes.get(height as usize).copied()
+    }
+
+    pub fn header_at(&self, height: u32) -> Option<&BlockHeader> {
+        self.headers.get(height as usize)
+    }
+
+    pub fn height_of(&self, hash: &BlockHash) -> Option<u32> {
+        self.heights.get(hash).copied()
+    }
+
+    /// Heights and hashes of the blocks in `heights` that we have headers for.
+    pub fn range(&self, heights: Range<u32>) -> Vec<(u32, BlockHash)> {
+        heights
+            .filter_map(|height| Some((height, self.hash_at(height)?)))
+            .collect()
+    }
+
+    /// Block locator for `getheaders`: the last ten blocks, then exponentially
+    /// sparser back to genesis.
+    pub fn locator(&self) -> Vec<BlockHash> {
+        let mut locator = Vec::new();
+        let mut height = self.height() as i64;
+        let mut step = 1;
+        while height > 0 {
+            locator.push(self.hashes[height as usize]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height -= step;
+        }
+        locator.push(self.hashes[0]);
+        locator
+    }
+
+    /// Appends headers which connect to the tip, skipping ones already known.
+    /// Returns how many were added.
+    pub fn extend(&mut self, headers: &[BlockHeader]) -> Result<usize> {
+        let mut added = 0;
+        for header in headers {
+            let hash = header.block_hash();
+            if self.heights.contains_key(&hash) {
+                continue;
+            }
+            if header.prev_blockhash != self.tip() {
+                return Err(Error::InvalidHeader(format!(
+                    "{} does not connect to tip {}",
+                    hash,
+                    self.tip()
+                )));
+            }
+            if !header.check_proof_of_work() {
+                return Err(Error::InvalidHeader(format!(
+                    "{} has insufficient proof of work",
+                    hash
+                )));
+            }
+            self.heights.insert(hash, self.headers.len() as u32);
+            self.headers.push(*header);
+            self.hashes.push(hash);
+            added += 1;
+        }
+        Ok(added)
+    }
+}
+
+/// Downloads headers from `peer` until it has no more to give us, or only
+/// headers we already have. Fails with a timed out IO error if it takes
+/// longer than `timeout` to answer a `getheaders`.
+pub async fn sync_headers<T: Transport>(
+    peer: &mut Peer<T>,
+    chain: &mut HeaderChain,
+    timeout: Duration,
+) -> Result<()> {
+    loop {
+        peer.send(Payload::GetHeaders(GetHeadersMessage {
+            version: PROTOCOL_VERSION as u32,
+            locator: chain.locator(),
+            stop_hash: Hash256::default(),
+        }))
+        .await?;
+
+        let deadline = Instant::now() + timeout;
+        let headers = loop {
+            match timeout_at(deadline, peer.recv()).await {
+                Ok(Ok(Payload::Headers(headers))) => break headers,
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+            }
+        };
+        let added = chain.extend(&headers)?;
+        if added == 0 || headers.len() < MAX_HEADERS {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::peer::PeerConfig;
+    use crate::bitcoin::ErrorKind;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn genesis() {
+        assert_eq!(
+            HeaderChain::new(Network::Mainnet).tip().to_string(),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert_eq!(
+            HeaderChain::new(Network::Regtest).tip().to_string(),
+            "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"
+        );
+        assert!(BlockHeader::genesis(Network::Mainnet).check_proof_of_work());
+        assert!(BlockHeader::genesis(Network::Signet).check_proof_of_work());
+    }
+
+    fn mine(chain: &HeaderChain, count: u32) -> Vec<BlockHeader> {
+        let mut headers = Vec::new();
+        let mut prev = chain.tip();
+        for time in 0..count {
+            let mut header = BlockHeader {
+                prev_blockhash: prev,
+                time,
+                ..BlockHeader::genesis(Network::Regtest)
+            };
+            while !header.check_proof_of_work() {
+                header.nonce += 1;
+            }
+            prev = header.block_hash();
+            headers.push(header);
+        }
+        headers
+    }
+
+    #[test]
+    fn extend_and_locate() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let headers = mine(&chain, 30);
+        let prev = headers[29].block_hash();
+
+        assert_eq!(chain.extend(&headers).unwrap(), 30);
+        assert_eq!(chain.extend(&headers[..5]).unwrap(), 0);
+        assert_eq!(chain.height(), 30);
+        assert_eq!(chain.height_of(&prev), Some(30));
+
+        let locator = chain.locator();
+        assert_eq!(locator[0], chain.tip());
+        assert_eq!(locator[10], chain.hash_at(19).unwrap());
+        assert_eq!(locator.last(), chain.hash_at(0).as_ref());
+
+        let orphan = BlockHeader {
+            prev_blockhash: Hash256::hash(b"unknown"),
+            ..headers[0]
+        };
+        assert!(chain.extend(&[orphan]).is_err());
+    }
+
+    #[tokio::test]
+    async fn sync_over_memory() {
+        let config = PeerConfig {
+            network: Network::Regtest,
+            ..Default::default()
+        };
+        let (mut peer, mut node) = Peer::pair("192.0.2.1:18444".parse().unwrap(), &config, &config)
+            .await
+            .unwrap();
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let headers = mine(&chain, 5);
+        let served = headers.clone();
+        tokio::spawn(async move {
+            while let Ok(payload) = node.recv().await {
+                if let Payload::GetHeaders(_) = payload {
+                    node.send(Payload::Headers(served.clone())).await.unwrap();
+                }
+            }
+        });
+
+        sync_headers(&mut peer, &mut chain, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(chain.height(), 5);
+        assert_eq!(chain.tip(), headers[4].block_hash());
+    }
+
+    #[tokio::test]
+    async fn stops_on_known_headers() {
+        let config = PeerConfig {
+            network: Network::Regtest,
+            ..Default::default()
+        };
+        let (mut peer, mut node) = Peer::pair("192.0.2.1:18444".parse().unwrap(), &config, &config)
+            .await
+            .unwrap();
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let served = mine(&chain, MAX_HEADERS as u32);
+        chain.extend(&served).unwrap();
+        // Always the same full batch, which would be asked for forever.
+        tokio::spawn(async move {
+            while let Ok(payload) = node.recv().await {
+                if let Payload::GetHeaders(_) = payload {
+                    node.send(Payload::Headers(served.clone())).await.unwrap();
+                }
+            }
+        });
+
+        sync_headers(&mut peer, &mut chain, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(chain.height(), MAX_HEADERS as u32);
+    }
+
+    #[tokio::test]
+    async fn times_out_without_headers() {
+        let config = PeerConfig {
+            network: Network::Regtest,
+            ..Default::default()
+        };
+        let (mut peer, mut node) = Peer::pair("192.0.2.1:18444".parse().unwrap(), &config, &config)
+            .await
+            .unwrap();
+        // Anything but headers.
+        tokio::spawn(async move {
+            while let Ok(payload) = node.recv().await {
+                if let Payload::GetHeaders(_) = payload {
+                    node.send(Payload::SendHeaders).await.unwrap();
+                }
+            }
+        });
+
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let error = sync_headers(&mut peer, &mut chain, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Io);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::bitcoin::peer::{Peer, Transport};
+use crate::bitcoin::{Inventory, InventoryKind, Payload, Result, ServiceFlags, Transaction, Txid};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// How long to wait for a requested transaction before asking another peer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default)]
+pub struct Observation {
+    /// When each peer first announced or sent us the transaction.
+    pub first_seen: HashMap<SocketAddr, SystemTime>,
+    pub transaction: Option<Transaction>,
+}
+
+impl Observation {
+    /// The earliest time any peer told us about the transaction.
+    pub fn first_seen_at(&self) -> Option<SystemTime> {
+        self.first_seen.values().min().copied()
+    }
+}
+
+/// Collects transaction announcements from several peers, fetching each
+/// transaction once and recording when every peer first relayed it.
+#[derive(Debug, Default)]
+pub struct MempoolWatcher {
+    observations: HashMap<Txid, Observation>,
+    requested: HashMap<Txid, (SocketAddr, SystemTime)>,
+    fee_filters: HashMap<SocketAddr, u64>,
+}
+
+impl MempoolWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds a payload received from `peer` into the watcher. Returns a
+    /// `getdata` request to send back to that peer, if any transactions it
+    /// announced still need fetching.
+    pub fn observe(&mut self, peer: SocketAddr, payload: &Payload) -> Option<Payload> {
+        self.observe_at(peer, payload, SystemTime::now())
+    }
+
+    fn observe_at(
+        &mut self,
+        peer: SocketAddr,
+        payload: &Payload,
+        now: SystemTime,
+    ) -> Option<Payload> {
+        match payload {
+            Payload::Inv(inventory) => {
+                let mut wanted = Vec::new();
+                for inv in inventory {
+                    if !matches!(inv.kind, InventoryKind::Tx | InventoryKind::WitnessTx) {
+                        continue;
+                    }
+                    if self.record(inv.hash, peer, now) {
+                        self.requested.insert(inv.hash, (peer, now));
+                        wanted.push(Inventory {
+                            kind: InventoryKind::WitnessTx,
+                            hash: inv.hash,
+                        });
+                    }
+                }
+                (!wanted.is_empty()).then_some(Payload::GetData(wanted))
+            }
+            Payload::Tx(tx) => {
+                let txid = tx.txid();
+                self.record(txid, peer, now);
+                self.requested.remove(&txid);
+                self.observations.entry(txid).or_default().transaction = Some(tx.clone());
+                None
+            }
+            Payload::NotFound(inventory) => {
+                for inv in inventory {
+                    if matches!(self.requested.get(&inv.hash), Some((from, _)) if *from == peer) {
+                        self.requested.remove(&inv.hash);
+                    }
+                }
+                None
+            }
+            Payload::FeeFilter(fee_rate) => {
+                self.fee_filters.insert(peer, *fee_rate);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Records that `peer` knows about `txid` and returns whether the
+    /// transaction still has to be requested.
+    fn record(&mut self, txid: Txid, peer: SocketAddr, now: SystemTime) -> bool {
+        let observation = self.observations.entry(txid).or_default();
+        observation.first_seen.entry(peer).or_insert(now);
+        if observation.transaction.is_some() {
+            return false;
+        }
+        match self.requested.get(&txid) {
+            Some((_, at)) => now.duration_since(*at).unwrap_or_default() >= REQUEST_TIMEOUT,
+            None => true,
+        }
+    }
+
+    pub fn observation(&self, txid: &Txid) -> Option<&Observation> {
+        self.observations.get(txid)
+    }
+
+    pub fn observations(&self) -> impl Iterator<Item = (&Txid, &Observation)> {
+        self.observations.iter()
+    }
+
+    /// The fee rate (sat/kvB) each peer asked us to respect via `feefilter`.
+    pub fn fee_filter(&self, peer: &SocketAddr) -> Option<u64> {
+        self.fee_filters.get(peer).copied()
+    }
+
+    /// Drops everything the watcher has collected, e.g. after exporting it.
+    /// Fee filters are kept: they describe the peers still connected rather
+    /// than what they relayed, and a peer only sends a new one when its rate
+    /// changes.
+    pub fn clear(&mut self) {
+        self.observations.clear();
+        self.requested.clear();
+    }
+}
+
+/// Drives a single peer connection, feeding everything it relays into the
+/// shared watcher until the connection fails. Peers advertising NODE_BLOOM are
+/// asked for the contents of their mempool first; others would disconnect us
+/// for it.
+pub async fn watch<T: Transport>(
+    watcher: Arc<Mutex<MempoolWatcher>>,
+    mut peer: Peer<T>,
+) -> Result<()> {
+    if peer.version().services.contains(ServiceFlags::BLOOM) {
+        peer.send(Payload::Mempool).await?;
+    }
+    loop {
+        let payload = peer.recv().await?;
+        let reply = watcher.lock().unwrap().observe(peer.addr(), &payload);
+        if let Some(reply) = reply {
+            peer.send(reply).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::{Hash256, TxOut};
+    use pretty_assertions::assert_eq;
+
+    fn tx() -> Transaction {
+        Transaction {
+            version: 1,
+            inputs: vec![],
+            outputs: vec![TxOut {
+                value: 1,
+                script_pubkey: vec![0x51].into(),
+            }],
+            lock_time: 0,
+        }
+    }
+
+    fn inv(txid: Txid) -> Payload {
+        Payload::Inv(vec![Inventory {
+            kind: InventoryKind::Tx,
+            hash: txid,
+        }])
+    }
+
+    #[test]
+    fn deduplicates_across_peers() {
+        let a: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:8333".parse().unwrap();
+        let tx = tx();
+        let txid = tx.txid();
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let t1 = t0 + Duration::from_secs(2);
+
+        let mut watcher = MempoolWatcher::new();
+        assert_eq!(
+            watcher.observe_at(a, &inv(txid), t0),
+            Some(Payload::GetData(vec![Inventory {
+                kind: InventoryKind::WitnessTx,
+                hash: txid,
+            }]))
+        );
+        assert_eq!(watcher.observe_at(b, &inv(txid), t1), None);
+        assert_eq!(watcher.observe_at(a, &Payload::Tx(tx.clone()), t1), None);
+
+        let observation = watcher.observation(&txid).unwrap();
+        assert_eq!(observation.transaction, Some(tx));
+        assert_eq!(observation.first_seen[&a], t0);
+        assert_eq!(observation.first_seen[&b], t1);
+        assert_eq!(observation.first_seen_at(), Some(t0));
+
+        watcher.observe_at(b, &Payload::FeeFilter(1_000), t1);
+        watcher.clear();
+        assert!(watcher.observation(&txid).is_none());
+        assert_eq!(watcher.fee_filter(&b), Some(1_000));
+    }
+
+    #[test]
+    fn refetches_after_notfound() {
+        let a: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:8333".parse().unwrap();
+        let txid = Hash256::hash(b"tx");
+        let now = SystemTime::UNIX_EPOCH;
+
+        let mut watcher = MempoolWatcher::new();
+        assert!(watcher.observe_at(a, &inv(txid), now).is_some());
+        let notfound = Payload::NotFound(vec![Inventory {
+            kind: InventoryKind::WitnessTx,
+            hash: txid,
+        }]);
+        assert_eq!(watcher.observe_at(a, &notfound, now), None);
+        assert!(watcher.observe_at(b, &inv(txid), now).is_some());
+    }
+}
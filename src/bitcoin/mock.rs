@@ -0,0 +1,333 @@
+//! A scriptable fake node for testing against without network access.
+
+use crate::bitcoin::headers::MAX_HEADERS;
+use crate::bitcoin::peer::{negotiate, PeerConfig, Transport};
+use crate::bitcoin::{
+    Address, BitcoinCodec, Block, BlockHash, BlockHeader, CodecEvent, Command, Context, Error,
+    GetHeadersMessage, Hash256, InventoryKind, LazyBlock, Message, Network, Payload, Result,
+    Transaction, TxIn, TxOut,
+};
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio_util::codec::Framed;
+
+/// Compact target every fixture block is mined at, as on regtest.
+const FIXTURE_BITS: u32 = 0x207fffff;
+
+/// One step of the script a mock node runs after the handshake.
+#[derive(Debug, Clone)]
+pub enum Action {
+    Send(Payload),
+    /// Writes the bytes as they are.
+    Raw(Vec<u8>),
+    /// Sends the payload with a checksum that doesn't match it.
+    BadChecksum(Payload),
+    /// Sends the payload under another magic.
+    WrongMagic(u32, Payload),
+    /// Sends the payload in pieces of at most this many bytes, flushing each.
+    Split(Payload, usize),
+    Delay(Duration),
+    /// Waits for the client to send a message with this command.
+    Expect(Command),
+    /// Closes the connection without serving anything else.
+    Disconnect,
+}
+
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    /// Identity the node announces, as for a `Peer`.
+    pub peer: PeerConfig,
+    /// Answer the version handshake before running the script.
+    pub handshake: bool,
+    /// Blocks on top of the genesis block, served for `getheaders` and
+    /// `getdata`. See `fixture_chain`.
+    pub chain: Vec<Block>,
+    /// Addresses handed out for `getaddr`.
+    pub addresses: Vec<Address<u32>>,
+    pub script: Vec<Action>,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            peer: PeerConfig {
+                network: Network::Regtest,
+                user_agent: "/mock:0.1.0/".to_string(),
+                ..PeerConfig::default()
+            },
+            handshake: true,
+            chain: Vec::new(),
+            addresses: Vec::new(),
+            script: Vec::new(),
+        }
+    }
+}
+
+/// Builds `length` blocks on top of the genesis block of `network`, each with
+/// a single transaction and mined at regtest difficulty.
+pub fn fixture_chain(network: Network, length: u32) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::with_capacity(length as usize);
+    let mut prev = BlockHeader::genesis(network).block_hash();
+    for height in 1..=length {
+        let coinbase = Transaction {
+            version: 1,
+            inputs: vec![TxIn {
+                script_sig: height.to_le_bytes().to_vec().into(),
+                ..Default::default()
+            }],
+            outputs: vec![TxOut {
+                value: 50_0000_0000,
+                script_pubkey: vec![0x51].into(),
+            }],
+            lock_time: 0,
+        };
+        let mut block = Block {
+            header: BlockHeader {
+                version: 4,
+                prev_blockhash: prev,
+                merkle_root: Hash256::default(),
+                time: 1_600_000_000 + height * 600,
+                bits: FIXTURE_BITS,
+                nonce: 0,
+            },
+            transactions: vec![coinbase],
+        };
+        block.header.merkle_root = block.compute_merkle_root();
+        while !block.header.check_proof_of_work() {
+            block.header.nonce += 1;
+        }
+        prev = block.block_hash();
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// A fake node. After the handshake and its script it answers pings and
+/// serves its chain until the client goes away.
+#[derive(Debug, Clone)]
+pub struct MockNode {
+    config: Arc<MockConfig>,
+}
+
+impl MockNode {
+    pub fn new(config: MockConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+
+    /// Accepts connections on `addr` in the background, serving each on its
+    /// own task. Returns the address actually bound, for binding port 0.
+    pub async fn listen(&self, addr: SocketAddr) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let local = listener.local_addr()?;
+        let node = self.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let node = node.clone();
+                tokio::spawn(async move { node.serve(stream).await });
+            }
+        });
+        Ok(local)
+    }
+
+    /// Serves a single connection. Returns everything the client sent, once
+    /// it disconnects or the script does.
+    pub async fn serve<T: Transport>(&self, transport: T) -> Result<Vec<Payload>> {
+        let config = &self.config;
+        let mut connection = Connection {
+            stream: Framed::new(transport, BitcoinCodec::new(config.peer.context())),
+            magic: config.peer.network.magic(),
+            received: Vec::new(),
+        };
+        if config.handshake {
+            connection.handshake(&config.peer).await?;
+        }
+        for action in &config.script {
+            if !connection.run(action).await? {
+                return Ok(connection.received);
+            }
+        }
+        while let Some(payload) = connection.recv().await? {
+            for reply in self.reply(payload) {
+                connection.send(reply).await?;
+            }
+        }
+        Ok(connection.received)
+    }
+
+    fn reply(&self, payload: Payload) -> Vec<Payload> {
+        match payload {
+            Payload::Ping(nonce) => vec![Payload::Pong(nonce)],
+            Payload::GetAddr => vec![Payload::Addr(self.config.addresses.clone())],
+            Payload::GetHeaders(request) => vec![Payload::Headers(self.headers(&request))],
+            Payload::GetData(inventory) => {
+                let mut replies = Vec::new();
+                let mut missing = Vec::new();
+                for item in inventory {
+                    let block = match item.kind {
+                        InventoryKind::Block | InventoryKind::WitnessBlock => {
+                            self.block(&item.hash)
+                        }
+                        _ => None,
+                    };
+                    match block {
+                        Some(block) => replies.push(Payload::Block(LazyBlock::from(block))),
+                        None => missing.push(item),
+                    }
+                }
+                if !missing.is_empty() {
+                    replies.push(Payload::NotFound(missing));
+                }
+                replies
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Headers following the first locator hash we know, like Core does.
+    fn headers(&self, request: &GetHeadersMessage) -> Vec<BlockHeader> {
+        let chain = &self.config.chain;
+        let genesis = BlockHeader::genesis(self.config.peer.network).block_hash();
+        let start = request
+            .locator
+            .iter()
+            .find_map(|hash| {
+                if *hash == genesis {
+                    return Some(0);
+                }
+                chain
+                    .iter()
+                    .position(|block| block.block_hash() == *hash)
+                    .map(|index| index + 1)
+            })
+            .unwrap_or(0);
+        let mut headers = Vec::new();
+        for block in chain[start..].iter().take(MAX_HEADERS) {
+            headers.push(block.header);
+            if block.block_hash() == request.stop_hash {
+                break;
+            }
+        }
+        headers
+    }
+
+    fn block(&self, hash: &BlockHash) -> Option<&Block> {
+        self.config
+            .chain
+            .iter()
+            .find(|block| block.block_hash() == *hash)
+    }
+}
+
+struct Connection<T> {
+    stream: Framed<T, BitcoinCodec>,
+    magic: u32,
+    received: Vec<Payload>,
+}
+
+impl<T: Transport> Connection<T> {
+    async fn handshake(&mut self, config: &PeerConfig) -> Result<()> {
+        let version = loop {
+            match self.recv().await?.ok_or(Error::ConnectionClosed)? {
+                Payload::Version(version) => break version,
+                _ => continue,
+            }
+        };
+        let addr = SocketAddr::new(version.addr_from.ip, version.addr_from.port.clone().into());
+        self.send(Payload::Version(config.version_message(addr)))
+            .await?;
+        self.send(Payload::VerAck).await?;
+        while !matches!(
+            self.recv().await?.ok_or(Error::ConnectionClosed)?,
+            Payload::VerAck
+        ) {}
+
+        let context = negotiate(self.stream.codec().context(), &version);
+        self.stream.codec_mut().set_context(context);
+        // Like Core, ask for headers announcements (BIP130).
+        if context.version >= 70012 {
+            self.send(Payload::SendHeaders).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns false if the connection should be closed.
+    async fn run(&mut self, action: &Action) -> Result<bool> {
+        match action {
+            Action::Send(payload) => self.send(payload.clone()).await?,
+            Action::Raw(bytes) => self.write(bytes).await?,
+            Action::BadChecksum(payload) => {
+                let mut frame = self.frame(self.magic, payload)?;
+                frame[20] ^= 0xff;
+                self.write(&frame).await?;
+            }
+            Action::WrongMagic(magic, payload) => {
+                let frame = self.frame(*magic, payload)?;
+                self.write(&frame).await?;
+            }
+            Action::Split(payload, size) => {
+                let frame = self.frame(self.magic, payload)?;
+                for piece in frame.chunks((*size).max(1)) {
+                    self.write(piece).await?;
+                    tokio::task::yield_now().await;
+                }
+            }
+            Action::Delay(duration) => tokio::time::sleep(*duration).await,
+            Action::Expect(command) => loop {
+                let payload = self.recv().await?.ok_or(Error::ConnectionClosed)?;
+                if payload.command() == *command {
+                    break;
+                }
+            },
+            Action::Disconnect => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn frame(&self, magic: u32, payload: &Payload) -> Result<BytesMut> {
+        let message = Message::new(magic, payload.command(), payload.clone())?;
+        let mut frame = BytesMut::new();
+        message.encode_into(&mut frame, self.context())?;
+        Ok(frame)
+    }
+
+    fn context(&self) -> &Context {
+        self.stream.codec().context()
+    }
+
+    async fn send(&mut self, payload: Payload) -> Result<()> {
+        let message = Message::new(self.magic, payload.command(), payload)?;
+        self.stream.send(message).await
+    }
+
+    /// Writes past the codec, after anything it still buffers.
+    async fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        SinkExt::<Message>::flush(&mut self.stream).await?;
+        let transport = self.stream.get_mut();
+        transport.write_all(bytes).await?;
+        transport.flush().await?;
+        Ok(())
+    }
+
+    /// The next payload from the client, or `None` once it disconnected.
+    async fn recv(&mut self) -> Result<Option<Payload>> {
+        loop {
+            match self.stream.next().await {
+                Some(Ok(CodecEvent::Message(message))) => {
+                    let payload = message.into_payload();
+                    self.received.push(payload.clone());
+                    return Ok(Some(payload));
+                }
+                Some(Ok(CodecEvent::Resynced { .. })) => continue,
+                Some(Err(e)) => return Err(e),
+                None => return Ok(None),
+            }
+        }
+    }
+}
@@ -1,30 +1,54 @@
+pub mod ban;
+mod block;
+pub mod blocking;
+pub mod broadcast;
+pub mod capture;
 mod codec;
+mod context;
+pub mod crawl;
 mod decode;
+pub mod download;
 mod encode;
 mod error;
+mod hash;
+pub mod headers;
+pub mod mempool;
+pub mod mock;
+mod network;
+mod payment_address;
+pub mod peer;
 mod protocol;
+mod script;
+#[cfg(feature = "serde")]
+mod serialize;
+mod services;
+mod transaction;
+pub mod user_agent;
 
+pub use block::*;
 pub use codec::*;
+pub use context::{Context, Limits};
 pub use decode::Decode;
-pub use encode::Encode;
-pub use error::{Error, Result};
+pub use encode::{BigEndian, Encode};
+pub use error::{Error, ErrorKind, Result, Violation};
+pub use handshake_derive::{Decode, Encode};
+pub use hash::*;
+pub use network::Network;
+pub use payment_address::*;
 pub use protocol::*;
+pub use script::*;
+pub use services::ServiceFlags;
+pub use transaction::*;
 
 pub trait Checksum {
     fn sha256(&self) -> u32;
 }
 
-impl Checksum for Vec<u8> {
+impl Checksum for [u8] {
     fn sha256(&self) -> u32 {
-        use sha2::{Digest, Sha256};
-        let mut hasher = Sha256::new();
-        hasher.update(self);
-        let result = hasher.finalize();
-        let mut hasher = Sha256::new();
-        hasher.update(result);
-        let result = hasher.finalize();
+        let hash = Hash256::hash(self);
         let mut bytes = [0; 4];
-        bytes.copy_from_slice(&result[0..4]);
+        bytes.copy_from_slice(&hash.as_bytes()[0..4]);
         u32::from_le_bytes(bytes)
     }
 }
@@ -0,0 +1,113 @@
+use crate::bitcoin::Error;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    pub fn magic(&self) -> u32 {
+        match self {
+            Self::Mainnet => 0xD9B4BEF9,
+            Self::Testnet => 0x0709110B,
+            Self::Signet => 0x40CF030A,
+            Self::Regtest => 0xDAB5BFFA,
+        }
+    }
+
+    /// The network whose messages start with `magic`, if it's one we know.
+    pub fn from_magic(magic: u32) -> Option<Self> {
+        [Self::Mainnet, Self::Testnet, Self::Signet, Self::Regtest]
+            .into_iter()
+            .find(|network| network.magic() == magic)
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Self::Mainnet => 8333,
+            Self::Testnet => 18333,
+            Self::Signet => 38333,
+            Self::Regtest => 18444,
+        }
+    }
+
+    /// Hostnames resolving to nodes to make first contact with.
+    pub fn dns_seeds(&self) -> &'static [&'static str] {
+        match self {
+            Self::Mainnet => &[
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "seed.bitcoinstats.com",
+                "seed.bitcoin.jonasschnelli.ch",
+                "seed.btc.petertodd.net",
+                "seed.bitcoin.sprovoost.nl",
+                "dnsseed.emzy.de",
+                "seed.bitcoin.wiz.biz",
+            ],
+            Self::Testnet => &[
+                "testnet-seed.bitcoin.jonasschnelli.ch",
+                "seed.tbtc.petertodd.net",
+                "seed.testnet.bitcoin.sprovoost.nl",
+                "testnet-seed.bluematt.me",
+            ],
+            Self::Signet => &["seed.signet.bitcoin.sprovoost.nl"],
+            Self::Regtest => &[],
+        }
+    }
+
+    /// Base58Check version byte of pay-to-pubkey-hash addresses.
+    pub fn p2pkh_prefix(&self) -> u8 {
+        match self {
+            Self::Mainnet => 0x00,
+            Self::Testnet | Self::Signet | Self::Regtest => 0x6f,
+        }
+    }
+
+    /// Base58Check version byte of pay-to-script-hash addresses.
+    pub fn p2sh_prefix(&self) -> u8 {
+        match self {
+            Self::Mainnet => 0x05,
+            Self::Testnet | Self::Signet | Self::Regtest => 0xc4,
+        }
+    }
+
+    /// Human-readable part of segwit addresses.
+    pub fn bech32_hrp(&self) -> &'static str {
+        match self {
+            Self::Mainnet => "bc",
+            Self::Testnet | Self::Signet => "tb",
+            Self::Regtest => "bcrt",
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Mainnet => "mainnet",
+            Self::Testnet => "testnet",
+            Self::Signet => "signet",
+            Self::Regtest => "regtest",
+        })
+    }
+}
+
+impl FromStr for Network {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "mainnet" | "main" | "bitcoin" => Ok(Self::Mainnet),
+            "testnet" | "test" => Ok(Self::Testnet),
+            "signet" => Ok(Self::Signet),
+            "regtest" => Ok(Self::Regtest),
+            _ => Err(Error::Parse(format!("unknown network {:?}", s))),
+        }
+    }
+}
@@ -0,0 +1,406 @@
+//! Human-readable payment addresses. Not to be confused with [`Address`],
+//! the network address of a peer.
+//!
+//! [`Address`]: crate::bitcoin::Address
+
+use crate::bitcoin::{Error, Hash256, Network, Opcode, Result, Script};
+use std::fmt;
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum AddressKind {
+    P2pkh([u8; 20]),
+    P2sh([u8; 20]),
+    Witness { version: u8, program: Vec<u8> },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct PaymentAddress {
+    pub network: Network,
+    pub kind: AddressKind,
+}
+
+impl PaymentAddress {
+    /// The address paid to by `script`, if it has one. Bare public keys,
+    /// multisig and OP_RETURN outputs do not.
+    pub fn from_script(script: &Script, network: Network) -> Option<Self> {
+        let bytes = script.as_bytes();
+        let kind = match bytes {
+            [0x76, 0xa9, 0x14, hash @ .., 0x88, 0xac] if hash.len() == 20 => {
+                AddressKind::P2pkh(hash.try_into().unwrap())
+            }
+            [0xa9, 0x14, hash @ .., 0x87] if hash.len() == 20 => {
+                AddressKind::P2sh(hash.try_into().unwrap())
+            }
+            _ => {
+                let (version, program) = script.witness_program()?;
+                if version == 0 && !matches!(program.len(), 20 | 32) {
+                    return None;
+                }
+                AddressKind::Witness {
+                    version,
+                    program: program.to_vec(),
+                }
+            }
+        };
+        Some(Self { network, kind })
+    }
+
+    pub fn script_pubkey(&self) -> Script {
+        match &self.kind {
+            AddressKind::P2pkh(hash) => [&[0x76, 0xa9, 0x14][..], hash, &[0x88, 0xac]]
+                .concat()
+                .into(),
+            AddressKind::P2sh(hash) => [&[0xa9, 0x14][..], hash, &[0x87]].concat().into(),
+            AddressKind::Witness { version, program } => {
+                let version = Opcode::from_small_int(*version).unwrap_or(Opcode::OP_0);
+                [&[version.0, program.len() as u8][..], program]
+                    .concat()
+                    .into()
+            }
+        }
+    }
+
+    /// Parses `s`, requiring it to belong to `network`.
+    pub fn parse(s: &str, network: Network) -> Result<Self> {
+        let address = s.parse::<Self>()?;
+        let matches = match address.kind {
+            AddressKind::P2pkh(_) | AddressKind::P2sh(_) => {
+                address.network.p2pkh_prefix() == network.p2pkh_prefix()
+            }
+            AddressKind::Witness { .. } => address.network.bech32_hrp() == network.bech32_hrp(),
+        };
+        if !matches {
+            return Err(Error::Parse(format!(
+                "{} is not a {:?} address",
+                s, network
+            )));
+        }
+        Ok(Self { network, ..address })
+    }
+}
+
+impl fmt::Display for PaymentAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            AddressKind::P2pkh(hash) => {
+                f.write_str(&base58check_encode(self.network.p2pkh_prefix(), hash))
+            }
+            AddressKind::P2sh(hash) => {
+                f.write_str(&base58check_encode(self.network.p2sh_prefix(), hash))
+            }
+            AddressKind::Witness { version, program } => {
+                let variant = match version {
+                    0 => Variant::Bech32,
+                    _ => Variant::Bech32m,
+                };
+                let mut data = vec![*version];
+                data.extend(convert_bits(program, 8, 5, true).unwrap());
+                f.write_str(&bech32_encode(self.network.bech32_hrp(), &data, variant))
+            }
+        }
+    }
+}
+
+/// Parses any address, guessing the network from its prefix. Testnet and
+/// signet share prefixes, so signet addresses come back as testnet ones.
+impl std::str::FromStr for PaymentAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        const NETWORKS: [Network; 3] = [Network::Mainnet, Network::Testnet, Network::Regtest];
+
+        if let Some((hrp, data, variant)) = bech32_decode(s) {
+            let network = NETWORKS
+                .into_iter()
+                .find(|network| network.bech32_hrp() == hrp)
+                .ok_or_else(|| Error::Parse(format!("unknown address prefix {:?}", hrp)))?;
+            let (&version, data) = data
+                .split_first()
+                .ok_or_else(|| Error::Parse("empty witness program".to_string()))?;
+            let program = convert_bits(data, 5, 8, false)
+                .ok_or_else(|| Error::Parse("invalid witness program padding".to_string()))?;
+            let valid = match version {
+                0 => variant == Variant::Bech32 && matches!(program.len(), 20 | 32),
+                1..=16 => variant == Variant::Bech32m && (2..=40).contains(&program.len()),
+                _ => false,
+            };
+            if !valid {
+                return Err(Error::Parse(format!("invalid segwit address {:?}", s)));
+            }
+            return Ok(Self {
+                network,
+                kind: AddressKind::Witness { version, program },
+            });
+        }
+
+        let data = base58check_decode(s)?;
+        let (&prefix, hash) = data
+            .split_first()
+            .filter(|(_, hash)| hash.len() == 20)
+            .ok_or_else(|| Error::Parse(format!("invalid address length {:?}", s)))?;
+        let hash = hash.try_into().unwrap();
+        NETWORKS
+            .into_iter()
+            .find_map(|network| {
+                let kind = if prefix == network.p2pkh_prefix() {
+                    AddressKind::P2pkh(hash)
+                } else if prefix == network.p2sh_prefix() {
+                    AddressKind::P2sh(hash)
+                } else {
+                    return None;
+                };
+                Some(Self { network, kind })
+            })
+            .ok_or_else(|| Error::Parse(format!("unknown address version {}", prefix)))
+    }
+}
+
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+fn base58check_encode(prefix: u8, payload: &[u8]) -> String {
+    let mut data = vec![prefix];
+    data.extend_from_slice(payload);
+    let checksum = Hash256::hash(&data);
+    data.extend_from_slice(&checksum.as_bytes()[..4]);
+
+    // Repeated division by 58, with digits kept least significant first.
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in &data {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = data.iter().take_while(|byte| **byte == 0).count();
+    std::iter::repeat_n(b'1', zeros)
+        .chain(
+            digits
+                .iter()
+                .rev()
+                .map(|digit| BASE58_ALPHABET[*digit as usize]),
+        )
+        .map(char::from)
+        .collect()
+}
+
+fn base58check_decode(s: &str) -> Result<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in s.bytes() {
+        let mut carry = BASE58_ALPHABET
+            .iter()
+            .position(|a| *a == c)
+            .ok_or_else(|| Error::Parse(format!("invalid base58 character {:?}", c as char)))?
+            as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = s.bytes().take_while(|c| *c == b'1').count();
+    let mut data = vec![0; zeros];
+    data.extend(bytes.iter().rev());
+
+    if data.len() < 4 {
+        return Err(Error::Parse("base58check data too short".to_string()));
+    }
+    let (payload, checksum) = data.split_at(data.len() - 4);
+    if Hash256::hash(payload).as_bytes()[..4] != *checksum {
+        return Err(Error::Parse("invalid base58check checksum".to_string()));
+    }
+    Ok(payload.to_vec())
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Variant {
+    Bech32,
+    Bech32m,
+}
+
+impl Variant {
+    fn constant(&self) -> u32 {
+        match self {
+            Self::Bech32 => 1,
+            Self::Bech32m => 0x2bc830a3,
+        }
+    }
+}
+
+const BECH32_CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn bech32_polymod(values: impl IntoIterator<Item = u8>) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut checksum = 1u32;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = (checksum & 0x1ffffff) << 5 ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+fn bech32_hrp_expand(hrp: &str) -> Vec<u8> {
+    hrp.bytes()
+        .map(|c| c >> 5)
+        .chain([0])
+        .chain(hrp.bytes().map(|c| c & 31))
+        .collect()
+}
+
+fn bech32_encode(hrp: &str, data: &[u8], variant: Variant) -> String {
+    let values = bech32_hrp_expand(hrp)
+        .into_iter()
+        .chain(data.iter().copied())
+        .chain([0; 6]);
+    let polymod = bech32_polymod(values) ^ variant.constant();
+    let checksum = (0..6).map(|i| ((polymod >> (5 * (5 - i))) & 31) as u8);
+
+    let mut address = format!("{}1", hrp);
+    address.extend(
+        data.iter()
+            .copied()
+            .chain(checksum)
+            .map(|value| BECH32_CHARSET[value as usize] as char),
+    );
+    address
+}
+
+/// Returns `None` for anything that isn't a well-formed bech32(m) string, so
+/// the caller can fall back to base58.
+fn bech32_decode(s: &str) -> Option<(String, Vec<u8>, Variant)> {
+    if s.len() > 90
+        || (s.bytes().any(|c| c.is_ascii_lowercase()) && s.bytes().any(|c| c.is_ascii_uppercase()))
+    {
+        return None;
+    }
+    let s = s.to_ascii_lowercase();
+    let (hrp, data) = s.rsplit_once('1')?;
+    if hrp.is_empty() || data.len() < 6 || !hrp.bytes().all(|c| (33..=126).contains(&c)) {
+        return None;
+    }
+    let data = data
+        .bytes()
+        .map(|c| BECH32_CHARSET.iter().position(|a| *a == c).map(|v| v as u8))
+        .collect::<Option<Vec<_>>>()?;
+    let variant = match bech32_polymod(
+        bech32_hrp_expand(hrp)
+            .into_iter()
+            .chain(data.iter().copied()),
+    ) {
+        1 => Variant::Bech32,
+        0x2bc830a3 => Variant::Bech32m,
+        _ => return None,
+    };
+    Some((hrp.to_string(), data[..data.len() - 6].to_vec(), variant))
+}
+
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut accumulator = 0u32;
+    let mut bits = 0;
+    let mut converted = Vec::new();
+    let max = (1 << to) - 1;
+    for value in data {
+        if (*value as u32) >> from != 0 {
+            return None;
+        }
+        accumulator = (accumulator << from) | *value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            converted.push(((accumulator >> bits) & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            converted.push(((accumulator << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || (accumulator << (to - bits)) & max != 0 {
+        return None;
+    }
+    Some(converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn roundtrip() {
+        let cases = [
+            (
+                "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+                "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac",
+                Network::Mainnet,
+            ),
+            (
+                "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+                "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+                Network::Mainnet,
+            ),
+            (
+                "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+                "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+                Network::Testnet,
+            ),
+            (
+                "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+                "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+                Network::Mainnet,
+            ),
+        ];
+        for (address, script, network) in cases {
+            let script = Script::from(hex::decode(script).unwrap());
+            let parsed = address.parse::<PaymentAddress>().unwrap();
+            assert_eq!(parsed.network, network);
+            assert_eq!(parsed.script_pubkey(), script);
+            let from_script = PaymentAddress::from_script(&script, network).unwrap();
+            assert_eq!(from_script.to_string(), address);
+        }
+    }
+
+    #[test]
+    fn p2sh_prefixes() {
+        let script =
+            Script::from(hex::decode("a914748284390f9e263a4b766a75d0633c50426eb87587").unwrap());
+        let mainnet = PaymentAddress::from_script(&script, Network::Mainnet).unwrap();
+        assert!(mainnet.to_string().starts_with('3'));
+        let testnet = PaymentAddress::from_script(&script, Network::Testnet).unwrap();
+        assert!(testnet.to_string().starts_with('2'));
+        assert_eq!(
+            testnet.to_string().parse::<PaymentAddress>().unwrap(),
+            testnet
+        );
+        assert!(PaymentAddress::parse(&mainnet.to_string(), Network::Regtest).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        for address in [
+            // Corrupted bech32 checksum.
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5",
+            // Mixed case.
+            "bc1qW508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+            // Taproot program with a bech32 instead of a bech32m checksum.
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd",
+            // Corrupted base58 checksum.
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb",
+        ] {
+            assert!(address.parse::<PaymentAddress>().is_err(), "{}", address);
+        }
+    }
+}
@@ -0,0 +1,413 @@
+use crate::bitcoin::capture::PeerCapture;
+use crate::bitcoin::user_agent::{Component, UserAgent};
+use crate::bitcoin::{
+    BitcoinCodec, CodecEvent, Context, Error, Limits, Message, Network, Payload, Result,
+    ServiceFlags, VersionMessage,
+};
+use futures::{SinkExt, StreamExt};
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+pub const PROTOCOL_VERSION: i32 = 70016;
+
+/// Capacity of each direction of an in-memory connection.
+const DUPLEX_BUFFER: usize = 1 << 20;
+
+/// Anything a peer connection can run over: TCP, TLS or SOCKS streams, Unix
+/// sockets, in-memory pipes.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Transport for T {}
+
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    pub network: Network,
+    pub user_agent: String,
+    pub services: ServiceFlags,
+    pub start_height: i32,
+    /// Ask the peer to announce transactions to us (BIP37 `relay` flag).
+    pub relay: bool,
+    pub limits: Limits,
+    /// Directory to record all messages to, laid out like Bitcoin Core's
+    /// `message_capture`. See `capture`.
+    pub capture: Option<PathBuf>,
+    /// Nonces of our handshakes in progress. Clones of a config share them,
+    /// so connections made with it can tell when they reach each other.
+    pub nonces: LocalNonces,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        Self {
+            network: Network::Mainnet,
+            user_agent: UserAgent::new().with(Component::handshake()).to_string(),
+            services: ServiceFlags::NONE,
+            start_height: 0,
+            relay: false,
+            limits: Limits::default(),
+            capture: None,
+            nonces: LocalNonces::default(),
+        }
+    }
+}
+
+impl PeerConfig {
+    /// The version message announcing us to the node at `addr`.
+    pub fn version_message(&self, addr: SocketAddr) -> VersionMessage {
+        VersionMessage::builder()
+            .services(self.services)
+            .addr_recv(addr)
+            .user_agent(&self.user_agent)
+            .start_height(self.start_height)
+            .relay(self.relay)
+            .build()
+    }
+
+    /// Codec for a connection to `addr`, capturing if configured to.
+    pub(crate) fn codec(&self, addr: SocketAddr) -> Result<BitcoinCodec> {
+        let mut codec = BitcoinCodec::new(self.context());
+        if let Some(root) = &self.capture {
+            codec.set_capture(Some(PeerCapture::open(root, addr)?));
+        }
+        Ok(codec)
+    }
+
+    pub(crate) fn context(&self) -> Context {
+        Context {
+            network: self.network,
+            limits: self.limits,
+            ..Context::default()
+        }
+    }
+}
+
+/// Nonces of the version messages we sent on connections still
+/// handshaking. A version coming back with one of them means we connected to
+/// ourselves, like dialing our own public address from behind NAT while
+/// listening. Bitcoin Core tracks the same to drop such connections.
+#[derive(Debug, Clone, Default)]
+pub struct LocalNonces(Arc<Mutex<HashSet<u64>>>);
+
+impl LocalNonces {
+    pub fn contains(&self, nonce: u64) -> bool {
+        self.0.lock().unwrap().contains(&nonce)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    /// Remembers `nonce` until the returned guard is dropped.
+    pub(crate) fn register(&self, nonce: u64) -> NonceGuard {
+        self.0.lock().unwrap().insert(nonce);
+        NonceGuard {
+            nonces: self.clone(),
+            nonce,
+        }
+    }
+}
+
+/// Forgets its nonce when dropped, however the handshake ended.
+pub(crate) struct NonceGuard {
+    nonces: LocalNonces,
+    nonce: u64,
+}
+
+impl Drop for NonceGuard {
+    fn drop(&mut self) {
+        self.nonces.0.lock().unwrap().remove(&self.nonce);
+    }
+}
+
+/// The version handshake, independent of how messages travel, so `Peer`
+/// and `SyncPeer` go through the same steps. Our version is sent first;
+/// everything received after that is fed to `receive` until `is_done`.
+pub(crate) struct Handshake {
+    nonces: LocalNonces,
+    /// Our version's nonce, remembered for as long as the handshake lasts.
+    _nonce: NonceGuard,
+    theirs: Option<VersionMessage>,
+    verack: bool,
+}
+
+impl Handshake {
+    /// Starts a handshake with the node at `addr`, along with the version
+    /// to send it.
+    pub(crate) fn start(config: &PeerConfig, addr: SocketAddr) -> (Self, Payload) {
+        let version = config.version_message(addr);
+        let handshake = Self {
+            nonces: config.nonces.clone(),
+            _nonce: config.nonces.register(version.nonce),
+            theirs: None,
+            verack: false,
+        };
+        (handshake, Payload::Version(version))
+    }
+
+    /// Takes a payload from the remote node and returns the reply to send,
+    /// if any. Fails with `Error::SelfConnection` if the node's version
+    /// carries the nonce of one of our own handshakes in progress.
+    pub(crate) fn receive(&mut self, payload: Payload) -> Result<Option<Payload>> {
+        match payload {
+            Payload::Version(_) if self.theirs.is_some() => {
+                Err(Error::Handshake("duplicate version message".to_string()))
+            }
+            Payload::Version(version) if self.nonces.contains(version.nonce) => {
+                Err(Error::SelfConnection)
+            }
+            Payload::Version(version) => {
+                self.theirs = Some(version);
+                Ok(Some(Payload::VerAck))
+            }
+            Payload::VerAck => {
+                self.verack = true;
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.theirs.is_some() && self.verack
+    }
+
+    /// The remote node's version, once the handshake is done.
+    pub(crate) fn finish(self) -> Option<VersionMessage> {
+        if self.verack {
+            self.theirs
+        } else {
+            None
+        }
+    }
+}
+
+/// Settles on the lower of both protocol versions and on witness data if the
+/// remote node offers it.
+pub(crate) fn negotiate(context: &Context, theirs: &VersionMessage) -> Context {
+    Context {
+        version: theirs.version.min(PROTOCOL_VERSION),
+        witness: theirs.services.contains(ServiceFlags::WITNESS),
+        ..*context
+    }
+}
+
+/// A connection to a remote node which has completed the version handshake.
+pub struct Peer<T = TcpStream> {
+    addr: SocketAddr,
+    network: Network,
+    stream: Framed<T, BitcoinCodec>,
+    version: VersionMessage,
+    skipped: usize,
+}
+
+impl Peer {
+    pub async fn connect(addr: SocketAddr, config: &PeerConfig) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        Self::handshake(stream, addr, config).await
+    }
+}
+
+impl Peer<DuplexStream> {
+    /// Connects two peers through an in-memory pipe and handshakes both
+    /// ends, so tests don't need sockets. Our end sees the other as `addr`.
+    /// The remote end stands for another node, so it never shares our nonces.
+    pub async fn pair(
+        addr: SocketAddr,
+        local: &PeerConfig,
+        remote: &PeerConfig,
+    ) -> Result<(Self, Self)> {
+        let (ours, theirs) = tokio::io::duplex(DUPLEX_BUFFER);
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let remote = PeerConfig {
+            nonces: LocalNonces::default(),
+            ..remote.clone()
+        };
+        tokio::try_join!(
+            Self::handshake(ours, addr, local),
+            Self::handshake(theirs, unspecified, &remote)
+        )
+    }
+}
+
+impl<T: Transport> Peer<T> {
+    /// Runs the version handshake over an established `transport` to the node
+    /// at `addr`. Fails with `Error::SelfConnection` if the node answers with
+    /// the nonce of one of our own handshakes in progress.
+    pub async fn handshake(transport: T, addr: SocketAddr, config: &PeerConfig) -> Result<Self> {
+        let mut stream = Framed::new(transport, config.codec(addr)?);
+        let magic = config.network.magic();
+        let (mut handshake, version) = Handshake::start(config, addr);
+        stream
+            .send(Message::new(magic, version.command(), version)?)
+            .await?;
+
+        let mut skipped = 0;
+        while !handshake.is_done() {
+            let message = match stream.next().await.ok_or(Error::ConnectionClosed)?? {
+                CodecEvent::Message(message) => message,
+                CodecEvent::Resynced {
+                    skipped: count,
+                    cause,
+                } => {
+                    skipped += count;
+                    match cause {
+                        Some(violation) => return Err(violation.into()),
+                        None => continue,
+                    }
+                }
+            };
+            if let Some(reply) = handshake.receive(message.into_payload())? {
+                stream
+                    .send(Message::new(magic, reply.command(), reply)?)
+                    .await?;
+            }
+        }
+
+        let version = handshake.finish().unwrap();
+        let context = negotiate(stream.codec().context(), &version);
+        stream.codec_mut().set_context(context);
+
+        Ok(Self {
+            addr,
+            network: config.network,
+            stream,
+            version,
+            skipped,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// State negotiated during the handshake.
+    pub fn context(&self) -> &Context {
+        self.stream.codec().context()
+    }
+
+    /// The version message the remote node sent during the handshake.
+    pub fn version(&self) -> &VersionMessage {
+        &self.version
+    }
+
+    pub async fn send(&mut self, payload: Payload) -> Result<()> {
+        let message = Message::new(self.network.magic(), payload.command(), payload)?;
+        self.stream.send(message).await
+    }
+
+    /// Bytes dropped so far because they didn't form valid frames.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Waits for the next message from the peer. Pings are answered transparently.
+    /// After an error the peer is to blame for, which can be reported to a
+    /// `BanManager`, the connection is closed. Garbage between frames is
+    /// skipped and counted in `skipped`; if the peer broke a rule with it, like
+    /// sending a bad checksum, that's returned once as `Error::Protocol` and
+    /// receiving can go on.
+    pub async fn recv(&mut self) -> Result<Payload> {
+        loop {
+            let message = match self.stream.next().await.ok_or(Error::ConnectionClosed)?? {
+                CodecEvent::Message(message) => message,
+                CodecEvent::Resynced { skipped, cause } => {
+                    self.skipped += skipped;
+                    match cause {
+                        Some(violation) => return Err(violation.into()),
+                        None => continue,
+                    }
+                }
+            };
+            match message.into_payload() {
+                Payload::Ping(nonce) => self.send(Payload::Pong(nonce)).await?,
+                payload => return Ok(payload),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::ErrorKind;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn handshake_steps() {
+        let config = PeerConfig::default();
+        let addr = SocketAddr::from(([192, 0, 2, 1], 8333));
+        let (mut handshake, ours) = Handshake::start(&config, addr);
+        let Payload::Version(ours) = ours else {
+            panic!("expected our version");
+        };
+        assert!(config.nonces.contains(ours.nonce));
+        let version = config.version_message(addr);
+        // Messages before the version are ignored, and the verack may come
+        // first.
+        assert_eq!(handshake.receive(Payload::SendHeaders).unwrap(), None);
+        assert_eq!(handshake.receive(Payload::VerAck).unwrap(), None);
+        assert!(!handshake.is_done());
+        assert_eq!(
+            handshake
+                .receive(Payload::Version(version.clone()))
+                .unwrap(),
+            Some(Payload::VerAck)
+        );
+        assert!(handshake.is_done());
+        assert!(matches!(
+            handshake.receive(Payload::Version(version.clone())),
+            Err(Error::Handshake(_))
+        ));
+        assert_eq!(handshake.finish(), Some(version));
+        assert!(config.nonces.is_empty());
+
+        // Our own version coming back means we reached ourselves.
+        let (mut handshake, ours) = Handshake::start(&config, addr);
+        assert!(matches!(
+            handshake.receive(ours),
+            Err(Error::SelfConnection)
+        ));
+    }
+
+    #[tokio::test]
+    async fn detects_self_connection() {
+        // Both ends share one config, like a listener and a dialer in the
+        // same process which reached each other through NAT.
+        let config = PeerConfig::default();
+        let (ours, theirs) = tokio::io::duplex(DUPLEX_BUFFER);
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 8333));
+        let (outbound, inbound) = tokio::join!(
+            Peer::handshake(ours, addr, &config),
+            Peer::handshake(theirs, addr, &config)
+        );
+        let errors = [outbound.err(), inbound.err()];
+        assert!(
+            errors
+                .iter()
+                .any(|error| matches!(error, Some(Error::SelfConnection))),
+            "{:?}",
+            errors
+        );
+        assert!(errors.iter().all(Option::is_some));
+        assert_eq!(
+            Error::SelfConnection.kind(),
+            ErrorKind::Local,
+            "we aren't misbehaving towards ourselves"
+        );
+        assert!(config.nonces.is_empty());
+
+        // Other nodes' nonces don't trip it, and are forgotten once done.
+        let (peer, _node) = Peer::pair(addr, &config, &config).await.unwrap();
+        assert!(!config.nonces.contains(peer.version().nonce));
+        assert!(config.nonces.is_empty());
+    }
+}
@@ -1,73 +1,202 @@
-use crate::bitcoin::{Checksum, Decode, Encode, Error, Result};
-use bytes::{Buf, BufMut};
+use crate::bitcoin::peer::PROTOCOL_VERSION;
+use crate::bitcoin::user_agent::{Component, UserAgent};
+use crate::bitcoin::{
+    BlockHash, BlockHeader, Checksum, Context, Decode, Encode, Error, Hash256, LazyBlock, Result,
+    ServiceFlags, Transaction, Violation, HEADER_SIZE,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Message {
     magic: u32,
     command: Command,
-    length: u32,
-    checksum: u32,
     payload: Payload,
 }
 
 impl Message {
-    pub fn new(magic: u32, command: Command, payload: Payload) -> Self {
-        let mut encoded = Vec::new();
-        let length = payload.encode(&mut encoded).unwrap() as u32;
-        let checksum = encoded.sha256();
-        Self {
+    /// Fails if `command` doesn't name `payload`.
+    pub fn new(magic: u32, command: Command, payload: Payload) -> Result<Self> {
+        if payload.command() != command {
+            return Err(Error::Command(format!(
+                "{:?} payload sent as {:?}",
+                payload.command(),
+                command
+            )));
+        }
+        Ok(Self {
             magic,
             command,
-            length,
-            checksum,
             payload,
-        }
+        })
+    }
+
+    pub fn magic(&self) -> u32 {
+        self.magic
+    }
+
+    pub fn command(&self) -> &Command {
+        &self.command
     }
 
     pub fn payload(&self) -> &Payload {
         &self.payload
     }
+
+    pub fn into_payload(self) -> Payload {
+        self.payload
+    }
+
+    /// Serializes the payload straight into `buffer` after a placeholder
+    /// header, then patches in its length and checksum.
+    /// Nothing is left in `buffer` if encoding fails.
+    pub fn encode_into(&self, buffer: &mut BytesMut, context: &Context) -> Result<usize> {
+        let start = buffer.len();
+        let result = self.write_frame(buffer, start, context);
+        if result.is_err() {
+            buffer.truncate(start);
+        }
+        result
+    }
+
+    fn write_frame(&self, buffer: &mut BytesMut, start: usize, context: &Context) -> Result<usize> {
+        let mut written = self.magic.encode_with(buffer, context)?;
+        written += self.command.encode_with(buffer, context)?;
+        written += 0u32.encode_with(buffer, context)?;
+        written += 0u32.encode_with(buffer, context)?;
+        let length = self.payload.encode_with(buffer, context)?;
+        let length =
+            u32::try_from(length).map_err(|_| Error::NotEnoughSpace("payload over 4 GiB"))?;
+        let checksum = buffer[start + HEADER_SIZE..].sha256();
+        buffer[start + 16..start + 20].copy_from_slice(&length.to_le_bytes());
+        buffer[start + 20..start + HEADER_SIZE].copy_from_slice(&checksum.to_le_bytes());
+        Ok(written + length as usize)
+    }
 }
 
 impl Encode for Message {
-    fn encode(&self, buffer: &mut impl BufMut) -> Result<usize> {
-        let mut written = self.magic.encode(buffer)?;
-        written += self.command.encode(buffer)?;
-        written += self.length.encode(buffer)?;
-        written += self.checksum.encode(buffer)?;
-        written += self.payload.encode(buffer)?;
+    fn encode_with(&self, buffer: &mut impl BufMut, context: &Context) -> Result<usize> {
+        // A `BufMut` can't be written to out of order, so the message is
+        // assembled first. The codec writes to its buffer directly.
+        let mut frame = BytesMut::new();
+        let written = self.encode_into(&mut frame, context)?;
+        if buffer.remaining_mut() < written {
+            return Err(Error::NotEnoughSpace("message"));
+        }
+        buffer.put_slice(&frame);
         Ok(written)
     }
 }
 
-impl Decode for Message {
-    fn decode(bytes: &mut impl Buf) -> Result<Self> {
-        let magic = u32::decode(bytes)?;
-        let command = Command::decode(bytes)?;
-        let length = u32::decode(bytes)?;
-        let checksum = u32::decode(bytes)?;
-        let payload = Payload::decode_command(command.clone(), bytes)?;
+impl Message {
+    /// Decodes a frame whose checksum the caller already verified.
+    pub(crate) fn decode_verified(bytes: &mut impl Buf, context: &Context) -> Result<Self> {
+        Self::decode_frame(bytes, context, false)
+    }
+
+    fn decode_frame(bytes: &mut impl Buf, context: &Context, verify: bool) -> Result<Self> {
+        let magic = u32::decode_with(bytes, context)?;
+        let command = Command::decode_with(bytes, context)?;
+        let length = u32::decode_with(bytes, context)? as usize;
+        let checksum = u32::decode_with(bytes, context)?;
+        if bytes.remaining() < length {
+            return Err(Error::NotEnoughBytes("payload"));
+        }
+        let mut payload = bytes.copy_to_bytes(length);
+        if verify {
+            let actual = payload.sha256();
+            if actual != checksum {
+                return Err(Violation::Checksum {
+                    expected: checksum,
+                    actual,
+                }
+                .into());
+            }
+        }
+        let payload =
+            Payload::decode_command(command.clone(), &mut payload, context).map_err(|e| {
+                e.in_field(command.name())
+                    .at_offset(HEADER_SIZE + length - payload.remaining())
+            })?;
         Ok(Message {
             magic,
             command,
-            length,
-            checksum,
             payload,
         })
     }
 }
 
+impl Decode for Message {
+    // The payload can't be derived: how to decode it depends on the command.
+    fn decode_with(bytes: &mut impl Buf, context: &Context) -> Result<Self> {
+        Self::decode_frame(bytes, context, true)
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum Command {
     Version,
     VerAck,
     SendHeaders,
     #[allow(dead_code)]
     SendCmpct,
+    Ping,
+    Pong,
+    Inv,
+    GetData,
+    NotFound,
+    Tx,
+    Mempool,
+    FeeFilter,
+    GetHeaders,
+    Headers,
+    Block,
+    GetAddr,
+    Addr,
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Version => "version",
+            Self::VerAck => "verack",
+            Self::SendHeaders => "sendheaders",
+            Self::SendCmpct => "sendcmpct",
+            Self::Ping => "ping",
+            Self::Pong => "pong",
+            Self::Inv => "inv",
+            Self::GetData => "getdata",
+            Self::NotFound => "notfound",
+            Self::Tx => "tx",
+            Self::Mempool => "mempool",
+            Self::FeeFilter => "feefilter",
+            Self::GetHeaders => "getheaders",
+            Self::Headers => "headers",
+            Self::Block => "block",
+            Self::GetAddr => "getaddr",
+            Self::Addr => "addr",
+        }
+    }
+}
+
+impl std::str::FromStr for Command {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut padded = [0; 12];
+        if s.len() > padded.len() {
+            return Err(Error::Command(format!("unhandled command: {:?}", s)));
+        }
+        padded[..s.len()].copy_from_slice(s.as_bytes());
+        Self::decode(&mut &padded[..])
+    }
 }
 
 impl Encode for Command {
-    fn encode(&self, buffer: &mut impl BufMut) -> Result<usize> {
+    fn encode_with(&self, buffer: &mut impl BufMut, _context: &Context) -> Result<usize> {
         if buffer.remaining_mut() < 12 {
             return Err(Error::NotEnoughSpace("Command"));
         }
@@ -76,13 +205,26 @@ impl Encode for Command {
             Self::VerAck => buffer.put_slice(b"verack\0\0\0\0\0\0"),
             Self::SendHeaders => buffer.put_slice(b"sendheaders\0"),
             Self::SendCmpct => return Err(Error::Command("unimplemented".to_string())),
+            Self::Ping => buffer.put_slice(b"ping\0\0\0\0\0\0\0\0"),
+            Self::Pong => buffer.put_slice(b"pong\0\0\0\0\0\0\0\0"),
+            Self::Inv => buffer.put_slice(b"inv\0\0\0\0\0\0\0\0\0"),
+            Self::GetData => buffer.put_slice(b"getdata\0\0\0\0\0"),
+            Self::NotFound => buffer.put_slice(b"notfound\0\0\0\0"),
+            Self::Tx => buffer.put_slice(b"tx\0\0\0\0\0\0\0\0\0\0"),
+            Self::Mempool => buffer.put_slice(b"mempool\0\0\0\0\0"),
+            Self::FeeFilter => buffer.put_slice(b"feefilter\0\0\0"),
+            Self::GetHeaders => buffer.put_slice(b"getheaders\0\0"),
+            Self::Headers => buffer.put_slice(b"headers\0\0\0\0\0"),
+            Self::Block => buffer.put_slice(b"block\0\0\0\0\0\0\0"),
+            Self::GetAddr => buffer.put_slice(b"getaddr\0\0\0\0\0"),
+            Self::Addr => buffer.put_slice(b"addr\0\0\0\0\0\0\0\0"),
         };
         Ok(12)
     }
 }
 
 impl Decode for Command {
-    fn decode(bytes: &mut impl Buf) -> Result<Self> {
+    fn decode_with(bytes: &mut impl Buf, _context: &Context) -> Result<Self> {
         if bytes.remaining() < 12 {
             return Err(Error::NotEnoughBytes("Command"));
         }
@@ -90,6 +232,19 @@ impl Decode for Command {
             b"version\0\0\0\0\0" => Ok(Command::Version),
             b"verack\0\0\0\0\0\0" => Ok(Command::VerAck),
             b"sendheaders\0" => Ok(Command::SendHeaders),
+            b"ping\0\0\0\0\0\0\0\0" => Ok(Command::Ping),
+            b"pong\0\0\0\0\0\0\0\0" => Ok(Command::Pong),
+            b"inv\0\0\0\0\0\0\0\0\0" => Ok(Command::Inv),
+            b"getdata\0\0\0\0\0" => Ok(Command::GetData),
+            b"notfound\0\0\0\0" => Ok(Command::NotFound),
+            b"tx\0\0\0\0\0\0\0\0\0\0" => Ok(Command::Tx),
+            b"mempool\0\0\0\0\0" => Ok(Command::Mempool),
+            b"feefilter\0\0\0" => Ok(Command::FeeFilter),
+            b"getheaders\0\0" => Ok(Command::GetHeaders),
+            b"headers\0\0\0\0\0" => Ok(Command::Headers),
+            b"block\0\0\0\0\0\0\0" => Ok(Command::Block),
+            b"getaddr\0\0\0\0\0" => Ok(Command::GetAddr),
+            b"addr\0\0\0\0\0\0\0\0" => Ok(Command::Addr),
             x => Err(Error::Command(format!(
                 "unhandled command: {:?}",
                 String::from_utf8_lossy(x)
@@ -99,162 +254,406 @@ impl Decode for Command {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(tag = "command", content = "payload", rename_all = "lowercase")
+)]
 pub enum Payload {
     Version(VersionMessage),
     VerAck,
     SendHeaders,
+    Ping(u64),
+    Pong(u64),
+    Inv(Vec<Inventory>),
+    GetData(Vec<Inventory>),
+    NotFound(Vec<Inventory>),
+    Tx(Transaction),
+    Mempool,
+    /// Minimum fee rate in satoshis per kilobyte the peer wants announced (BIP133).
+    FeeFilter(u64),
+    GetHeaders(GetHeadersMessage),
+    Headers(Vec<BlockHeader>),
+    /// Transactions are parsed on access, see `LazyBlock`.
+    Block(LazyBlock),
+    GetAddr,
+    /// Addresses of other nodes, with when they were last seen.
+    Addr(Vec<Address<u32>>),
 }
 
 impl Payload {
-    fn decode_command(command: Command, bytes: &mut impl Buf) -> Result<Self> {
+    pub fn command(&self) -> Command {
+        match self {
+            Self::Version(_) => Command::Version,
+            Self::VerAck => Command::VerAck,
+            Self::SendHeaders => Command::SendHeaders,
+            Self::Ping(_) => Command::Ping,
+            Self::Pong(_) => Command::Pong,
+            Self::Inv(_) => Command::Inv,
+            Self::GetData(_) => Command::GetData,
+            Self::NotFound(_) => Command::NotFound,
+            Self::Tx(_) => Command::Tx,
+            Self::Mempool => Command::Mempool,
+            Self::FeeFilter(_) => Command::FeeFilter,
+            Self::GetHeaders(_) => Command::GetHeaders,
+            Self::Headers(_) => Command::Headers,
+            Self::Block(_) => Command::Block,
+            Self::GetAddr => Command::GetAddr,
+            Self::Addr(_) => Command::Addr,
+        }
+    }
+
+    /// Decodes the payload of a `command` message, without its header.
+    pub fn decode_command(
+        command: Command,
+        bytes: &mut impl Buf,
+        context: &Context,
+    ) -> Result<Self> {
         match command {
             Command::Version => {
-                let version = VersionMessage::decode(bytes)?;
+                let version = VersionMessage::decode_with(bytes, context)?;
                 Ok(Payload::Version(version))
             }
             Command::VerAck => Ok(Payload::VerAck),
             Command::SendHeaders => Ok(Payload::SendHeaders),
             Command::SendCmpct => Ok(Payload::SendHeaders),
+            Command::Ping => Ok(Payload::Ping(u64::decode_with(bytes, context)?)),
+            Command::Pong => Ok(Payload::Pong(u64::decode_with(bytes, context)?)),
+            Command::Inv => Ok(Payload::Inv(decode_inventory(bytes, context)?)),
+            Command::GetData => Ok(Payload::GetData(decode_inventory(bytes, context)?)),
+            Command::NotFound => Ok(Payload::NotFound(decode_inventory(bytes, context)?)),
+            Command::Tx => Ok(Payload::Tx(Transaction::decode_with(bytes, context)?)),
+            Command::Mempool => Ok(Payload::Mempool),
+            Command::FeeFilter => Ok(Payload::FeeFilter(u64::decode_with(bytes, context)?)),
+            Command::GetHeaders => Ok(Payload::GetHeaders(GetHeadersMessage::decode_with(
+                bytes, context,
+            )?)),
+            Command::Headers => {
+                let count = decode_count(bytes, context, context.limits.max_headers, "headers")?;
+                if bytes.remaining() < count * (BlockHeader::SIZE + 1) {
+                    return Err(Error::NotEnoughBytes("headers"));
+                }
+                let mut headers = Vec::with_capacity(count);
+                for index in 0..count {
+                    headers.push(
+                        BlockHeader::decode_with(bytes, context)
+                            .map_err(|e| e.in_field(&index.to_string()))?,
+                    );
+                    // Transaction count, always zero in a headers message.
+                    VariableInt::decode_with(bytes, context)?;
+                }
+                Ok(Payload::Headers(headers))
+            }
+            Command::Block => Ok(Payload::Block(LazyBlock::decode_with(bytes, context)?)),
+            Command::GetAddr => Ok(Payload::GetAddr),
+            Command::Addr => {
+                let count = decode_count(bytes, context, context.limits.max_addresses, "addr")?;
+                let mut addresses = Vec::with_capacity(count);
+                for index in 0..count {
+                    addresses.push(
+                        Address::decode_with(bytes, context)
+                            .map_err(|e| e.in_field(&index.to_string()))?,
+                    );
+                }
+                Ok(Payload::Addr(addresses))
+            }
         }
     }
 }
 
+/// Reads a length prefix, rejecting it if it's over `limit`.
+fn decode_count(
+    bytes: &mut impl Buf,
+    context: &Context,
+    limit: usize,
+    what: &'static str,
+) -> Result<usize> {
+    let count = VariableInt::decode_with(bytes, context)?.0;
+    if count > limit as u64 {
+        return Err(Violation::Oversize {
+            what,
+            size: count,
+            limit: limit as u64,
+        }
+        .into());
+    }
+    Ok(count as usize)
+}
+
+fn decode_inventory(bytes: &mut impl Buf, context: &Context) -> Result<Vec<Inventory>> {
+    let count = decode_count(bytes, context, context.limits.max_inventory, "inventory")?;
+    if bytes.remaining() < count * Inventory::SIZE {
+        return Err(Error::NotEnoughBytes("inventory"));
+    }
+    let mut inventory = Vec::with_capacity(count);
+    for index in 0..count {
+        inventory.push(
+            Inventory::decode_with(bytes, context).map_err(|e| e.in_field(&index.to_string()))?,
+        );
+    }
+    Ok(inventory)
+}
+
 impl Encode for Payload {
-    fn encode(&self, buffer: &mut impl BufMut) -> Result<usize> {
+    fn encode_with(&self, buffer: &mut impl BufMut, context: &Context) -> Result<usize> {
         match self {
-            Self::Version(version) => version.encode(buffer),
-            Self::VerAck => ().encode(buffer),
-            Self::SendHeaders => ().encode(buffer),
+            Self::Version(version) => version.encode_with(buffer, context),
+            Self::VerAck => ().encode_with(buffer, context),
+            Self::SendHeaders => ().encode_with(buffer, context),
+            Self::Ping(nonce) | Self::Pong(nonce) => nonce.encode_with(buffer, context),
+            Self::Inv(inventory) | Self::GetData(inventory) | Self::NotFound(inventory) => {
+                inventory.encode_with(buffer, context)
+            }
+            Self::Tx(tx) => tx.encode_with(buffer, context),
+            Self::Mempool => ().encode_with(buffer, context),
+            Self::FeeFilter(fee_rate) => fee_rate.encode_with(buffer, context),
+            Self::GetHeaders(getheaders) => getheaders.encode_with(buffer, context),
+            Self::Headers(headers) => {
+                let mut written = VariableInt(headers.len() as u64).encode_with(buffer, context)?;
+                for header in headers {
+                    written += header.encode_with(buffer, context)?;
+                    written += VariableInt(0).encode_with(buffer, context)?;
+                }
+                Ok(written)
+            }
+            Self::Block(block) => block.encode_with(buffer, context),
+            Self::GetAddr => ().encode_with(buffer, context),
+            Self::Addr(addresses) => addresses.encode_with(buffer, context),
         }
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VersionMessage {
     pub version: i32,
-    pub services: u64,
+    pub services: ServiceFlags,
     pub timestamp: i64,
     pub addr_recv: Address<()>,
     pub addr_from: Address<()>,
     pub nonce: u64,
+    #[wire(decode_with = "decode_user_agent")]
     pub user_agent: VariableLengthString,
     pub start_height: i32,
+    /// Only sent from protocol version 70001 on (BIP37).
+    #[wire(condition = "*version >= 70001")]
     pub relay: bool,
 }
 
-impl Encode for VersionMessage {
-    fn encode(&self, buffer: &mut impl BufMut) -> Result<usize> {
-        let mut written = self.version.encode(buffer)?;
-        written += self.services.encode(buffer)?;
-        written += self.timestamp.encode(buffer)?;
-        written += self.addr_recv.encode(buffer)?;
-        written += self.addr_from.encode(buffer)?;
-        written += self.nonce.encode(buffer)?;
-        written += self.user_agent.encode(buffer)?;
-        written += self.start_height.encode(buffer)?;
-        written += self.relay.encode(buffer)?;
-        Ok(written)
+impl VersionMessage {
+    /// Starts from our protocol version, the current time, a random nonce and
+    /// this library's user agent. Everything can be overridden.
+    pub fn builder() -> VersionMessageBuilder {
+        VersionMessageBuilder::default()
     }
 }
 
-impl Decode for VersionMessage {
-    fn decode(bytes: &mut impl Buf) -> Result<Self> {
-        let version = i32::decode(bytes)?;
-        let services = u64::decode(bytes)?;
-        let timestamp = i64::decode(bytes)?;
-        let addr_recv = Address::decode(bytes)?;
-        let addr_from = Address::decode(bytes)?;
-        let nonce = u64::decode(bytes)?;
-        let user_agent = VariableLengthString::decode(bytes)?;
-        let start_height = i32::decode(bytes)?;
-        let relay = bool::decode(bytes)?;
-        Ok(VersionMessage {
-            version,
-            services,
-            timestamp,
-            addr_recv,
-            addr_from,
-            nonce,
-            user_agent,
-            start_height,
-            relay,
-        })
+#[derive(Debug, Clone)]
+pub struct VersionMessageBuilder {
+    message: VersionMessage,
+}
+
+impl Default for VersionMessageBuilder {
+    fn default() -> Self {
+        let unspecified = Address {
+            time: (),
+            services: ServiceFlags::NONE,
+            ip: Ipv6Addr::UNSPECIFIED.into(),
+            port: Port(0),
+        };
+        let user_agent = UserAgent::new().with(Component::handshake()).to_string();
+        Self {
+            message: VersionMessage {
+                version: PROTOCOL_VERSION,
+                services: ServiceFlags::NONE,
+                timestamp: 0,
+                addr_recv: unspecified.clone(),
+                addr_from: unspecified,
+                nonce: rand::random(),
+                user_agent: user_agent.as_str().into(),
+                start_height: 0,
+                relay: false,
+            },
+        }
+        .timestamp(SystemTime::now())
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+impl VersionMessageBuilder {
+    pub fn version(mut self, version: i32) -> Self {
+        self.message.version = version;
+        self
+    }
+
+    /// Services we offer, announced in `addr_from` too.
+    pub fn services(mut self, services: ServiceFlags) -> Self {
+        self.message.services = services;
+        self.message.addr_from.services = services;
+        self
+    }
+
+    pub fn timestamp(mut self, time: SystemTime) -> Self {
+        self.message.timestamp = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        self
+    }
+
+    /// The address we see the peer at. Peers use it to learn theirs.
+    pub fn addr_recv(mut self, addr: SocketAddr) -> Self {
+        self.message.addr_recv.ip = addr.ip();
+        self.message.addr_recv.port = addr.port().into();
+        self
+    }
+
+    /// Our own address. Bitcoin Core leaves it unspecified, as does the
+    /// default.
+    pub fn addr_from(mut self, addr: SocketAddr) -> Self {
+        self.message.addr_from.ip = addr.ip();
+        self.message.addr_from.port = addr.port().into();
+        self
+    }
+
+    /// Replaces the random nonce, which identifies connections to ourselves.
+    pub fn nonce(mut self, nonce: u64) -> Self {
+        self.message.nonce = nonce;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.message.user_agent = user_agent.into();
+        self
+    }
+
+    /// Height of our best block.
+    pub fn start_height(mut self, height: i32) -> Self {
+        self.message.start_height = height;
+        self
+    }
+
+    /// Asks the peer to announce transactions to us (BIP37).
+    pub fn relay(mut self, relay: bool) -> Self {
+        self.message.relay = relay;
+        self
+    }
+
+    pub fn build(self) -> VersionMessage {
+        self.message
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GetHeadersMessage {
+    pub version: u32,
+    /// Hashes of blocks we have, from the tip backwards with growing gaps.
+    pub locator: Vec<BlockHash>,
+    /// Last header wanted, or all zeroes for as many as the peer will send.
+    pub stop_hash: BlockHash,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Address<T> {
     pub time: T,
-    pub services: u64,
+    pub services: ServiceFlags,
     pub ip: std::net::IpAddr,
     pub port: Port,
 }
 
-impl<T> Encode for Address<T>
-where
-    T: Encode,
-{
-    fn encode(&self, buffer: &mut impl BufMut) -> Result<usize> {
-        let mut bytes = 0;
-        bytes += self.time.encode(buffer)?;
-        bytes += self.services.encode(buffer)?;
-        bytes += self.ip.encode(buffer)?;
-        bytes += self.port.encode(buffer)?;
-        Ok(bytes)
+#[derive(Clone, Debug, Default, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Port(#[wire(big_endian)] u16);
+
+impl From<u16> for Port {
+    fn from(port: u16) -> Self {
+        Self(port)
     }
 }
 
-impl<T> Decode for Address<T>
-where
-    T: Decode,
-{
-    fn decode(bytes: &mut impl Buf) -> Result<Self> {
-        let time = T::decode(bytes)?;
-        let services = u64::decode(bytes)?;
-        let ip = std::net::IpAddr::decode(bytes)?;
-        let port = Port::decode(bytes)?;
-        Ok(Address {
-            time,
-            services,
-            ip,
-            port,
-        })
+impl From<Port> for u16 {
+    fn from(port: Port) -> Self {
+        port.0
     }
 }
 
-#[derive(Clone, Debug, Default, Eq, PartialEq)]
-pub struct Port(u16);
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum InventoryKind {
+    Error,
+    Tx,
+    Block,
+    FilteredBlock,
+    CompactBlock,
+    /// Transaction announced by wtxid (BIP339).
+    Wtx,
+    WitnessTx,
+    WitnessBlock,
+    Unknown(u32),
+}
 
-impl From<u16> for Port {
-    fn from(port: u16) -> Self {
-        Self(port)
+impl From<u32> for InventoryKind {
+    fn from(kind: u32) -> Self {
+        match kind {
+            0 => Self::Error,
+            1 => Self::Tx,
+            2 => Self::Block,
+            3 => Self::FilteredBlock,
+            4 => Self::CompactBlock,
+            5 => Self::Wtx,
+            0x40000001 => Self::WitnessTx,
+            0x40000002 => Self::WitnessBlock,
+            x => Self::Unknown(x),
+        }
     }
 }
 
-impl Encode for Port {
-    fn encode(&self, buffer: &mut impl BufMut) -> Result<usize> {
-        if buffer.remaining_mut() < 2 {
-            return Err(Error::NotEnoughSpace("port"));
+impl From<InventoryKind> for u32 {
+    fn from(kind: InventoryKind) -> Self {
+        match kind {
+            InventoryKind::Error => 0,
+            InventoryKind::Tx => 1,
+            InventoryKind::Block => 2,
+            InventoryKind::FilteredBlock => 3,
+            InventoryKind::CompactBlock => 4,
+            InventoryKind::Wtx => 5,
+            InventoryKind::WitnessTx => 0x40000001,
+            InventoryKind::WitnessBlock => 0x40000002,
+            InventoryKind::Unknown(x) => x,
         }
-        buffer.put_u16(self.0);
-        Ok(2)
     }
 }
 
-impl Decode for Port {
-    fn decode(bytes: &mut impl Buf) -> Result<Self> {
-        if bytes.remaining() <= 2 {
-            return Err(Error::NotEnoughBytes("port"));
-        };
-        Ok(Self(bytes.get_u16()))
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Inventory {
+    pub kind: InventoryKind,
+    pub hash: Hash256,
+}
+
+impl Inventory {
+    pub const SIZE: usize = 36;
+}
+
+impl Encode for InventoryKind {
+    fn encode_with(&self, buffer: &mut impl BufMut, context: &Context) -> Result<usize> {
+        u32::from(*self).encode_with(buffer, context)
+    }
+}
+
+impl Decode for InventoryKind {
+    fn decode_with(bytes: &mut impl Buf, context: &Context) -> Result<Self> {
+        Ok(u32::decode_with(bytes, context)?.into())
     }
 }
 
 #[derive(Debug, Default, Clone, Eq, PartialEq)]
-struct VariableInt(u64);
+pub struct VariableInt(pub u64);
 
 impl Encode for VariableInt {
-    fn encode(&self, buffer: &mut impl BufMut) -> Result<usize> {
+    fn encode_with(&self, buffer: &mut impl BufMut, context: &Context) -> Result<usize> {
         if buffer.remaining_mut() < 1 {
             return Err(Error::NotEnoughSpace("variable int"));
         }
@@ -265,37 +664,36 @@ impl Encode for VariableInt {
             }
             0xFD..=0xFFFF => {
                 buffer.put_u8(0xFD);
-                Ok(1 + (self.0 as u16).encode(buffer)?)
+                Ok(1 + (self.0 as u16).encode_with(buffer, context)?)
             }
             0x10000..=0xFFFFFFFF => {
                 buffer.put_u8(0xFE);
-                Ok(1 + (self.0 as u32).encode(buffer)?)
+                Ok(1 + (self.0 as u32).encode_with(buffer, context)?)
             }
             _ => {
-                buffer.put_u8(0xFE);
-                self.0.encode(buffer)?;
-                Ok(1 + self.0.encode(buffer)?)
+                buffer.put_u8(0xFF);
+                Ok(1 + self.0.encode_with(buffer, context)?)
             }
         }
     }
 }
 
 impl Decode for VariableInt {
-    fn decode(bytes: &mut impl Buf) -> Result<Self> {
+    fn decode_with(bytes: &mut impl Buf, context: &Context) -> Result<Self> {
         if bytes.remaining() < 1 {
             return Err(Error::NotEnoughBytes("variable int"));
         }
         match bytes.get_u8() {
             0xFD => {
-                let number = u16::decode(bytes)?;
+                let number = u16::decode_with(bytes, context)?;
                 Ok(VariableInt(number as u64))
             }
             0xFE => {
-                let number = u32::decode(bytes)?;
+                let number = u32::decode_with(bytes, context)?;
                 Ok(VariableInt(number as u64))
             }
             0xFF => {
-                let number = u64::decode(bytes)?;
+                let number = u64::decode_with(bytes, context)?;
                 Ok(VariableInt(number))
             }
             x => Ok(VariableInt(x as u64)),
@@ -303,9 +701,56 @@ impl Decode for VariableInt {
     }
 }
 
+impl<T> Encode for Vec<T>
+where
+    T: Encode,
+{
+    fn encode_with(&self, buffer: &mut impl BufMut, context: &Context) -> Result<usize> {
+        let mut written = VariableInt(self.len() as u64).encode_with(buffer, context)?;
+        for item in self {
+            written += item.encode_with(buffer, context)?;
+        }
+        Ok(written)
+    }
+}
+
+/// Bytes of memory a vector may reserve before its items are decoded.
+const PREALLOCATE: usize = 64 * 1024;
+
+impl<T> Decode for Vec<T>
+where
+    T: Decode,
+{
+    fn decode_with(bytes: &mut impl Buf, context: &Context) -> Result<Self> {
+        let length = VariableInt::decode_with(bytes, context)?.0 as usize;
+        if bytes.remaining() < length {
+            return Err(Error::NotEnoughBytes("vector"));
+        }
+        // Every item takes at least a byte of input but may need far more
+        // memory, so only a bounded amount is reserved up front.
+        let mut items = Vec::with_capacity(length.min(PREALLOCATE / size_of::<T>().max(1)));
+        for index in 0..length {
+            items.push(T::decode_with(bytes, context).map_err(|e| e.in_field(&index.to_string()))?);
+        }
+        Ok(items)
+    }
+}
+
 #[derive(Debug, Default, Clone, Eq, PartialEq)]
 pub struct VariableLengthString(VariableInt, String);
 
+impl VariableLengthString {
+    pub fn as_str(&self) -> &str {
+        &self.1
+    }
+}
+
+impl std::fmt::Display for VariableLengthString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.1)
+    }
+}
+
 impl From<&str> for VariableLengthString {
     fn from(s: &str) -> Self {
         VariableLengthString(VariableInt(s.len() as u64), s.to_string())
@@ -313,8 +758,8 @@ impl From<&str> for VariableLengthString {
 }
 
 impl Encode for VariableLengthString {
-    fn encode(&self, buffer: &mut impl BufMut) -> Result<usize> {
-        let written = self.0.encode(buffer)?;
+    fn encode_with(&self, buffer: &mut impl BufMut, context: &Context) -> Result<usize> {
+        let written = self.0.encode_with(buffer, context)?;
         if buffer.remaining_mut() < self.1.len() {
             return Err(Error::NotEnoughSpace("variable length string"));
         }
@@ -323,20 +768,48 @@ impl Encode for VariableLengthString {
     }
 }
 
-impl Decode for VariableLengthString {
-    fn decode(bytes: &mut impl Buf) -> Result<Self> {
-        let length = VariableInt::decode(bytes)?;
-        if bytes.remaining() < length.0 as usize {
-            return Err(Error::NotEnoughBytes("variable length string"));
+impl VariableLengthString {
+    /// Decodes a string of at most `limit` bytes, named `what` in errors.
+    fn decode_bounded(
+        bytes: &mut impl Buf,
+        context: &Context,
+        limit: usize,
+        what: &'static str,
+    ) -> Result<Self> {
+        let length = decode_count(bytes, context, limit, what)?;
+        if bytes.remaining() < length {
+            return Err(Error::NotEnoughBytes(what));
         }
-        let str = String::from_utf8_lossy(&bytes.copy_to_bytes(length.0 as usize)[..]).into_owned();
-        Ok(Self(length, str))
+        let str = String::from_utf8_lossy(&bytes.copy_to_bytes(length)[..]).into_owned();
+        Ok(Self(VariableInt(length as u64), str))
     }
 }
 
+/// Any string has to fit in a message.
+impl Decode for VariableLengthString {
+    fn decode_with(bytes: &mut impl Buf, context: &Context) -> Result<Self> {
+        Self::decode_bounded(
+            bytes,
+            context,
+            context.limits.max_payload,
+            "variable length string",
+        )
+    }
+}
+
+fn decode_user_agent(bytes: &mut impl Buf, context: &Context) -> Result<VariableLengthString> {
+    VariableLengthString::decode_bounded(
+        bytes,
+        context,
+        context.limits.max_user_agent,
+        "user agent",
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bitcoin::ErrorKind;
     use pretty_assertions::assert_eq;
 
     #[test]
@@ -345,21 +818,23 @@ mod tests {
         let message = Message {
             magic: 3652501241,
             command: Command::Version,
-            length: 102,
-            checksum: 1105356096,
             payload: Payload::Version(VersionMessage {
                 version: 70016,
-                services: 1033,
+                services: ServiceFlags::NETWORK
+                    | ServiceFlags::WITNESS
+                    | ServiceFlags::NETWORK_LIMITED,
                 timestamp: 1680126222,
                 addr_recv: Address {
                     time: (),
-                    services: 0,
+                    services: ServiceFlags::NONE,
                     ip: "2a02:8308:900c:5900:b59b:b551:1c26:2a8".parse().unwrap(),
                     port: Port(56190),
                 },
                 addr_from: Address {
                     time: (),
-                    services: 1033,
+                    services: ServiceFlags::NETWORK
+                        | ServiceFlags::WITNESS
+                        | ServiceFlags::NETWORK_LIMITED,
                     ip: "::".parse().unwrap(),
                     port: Port(0),
                 },
@@ -384,21 +859,23 @@ mod tests {
             Message {
                 magic: 3652501241,
                 command: Command::Version,
-                length: 102,
-                checksum: 1105356096,
                 payload: Payload::Version(VersionMessage {
                     version: 70016,
-                    services: 1033,
+                    services: ServiceFlags::NETWORK
+                        | ServiceFlags::WITNESS
+                        | ServiceFlags::NETWORK_LIMITED,
                     timestamp: 1680126222,
                     addr_recv: Address {
                         time: (),
-                        services: 0,
+                        services: ServiceFlags::NONE,
                         ip: "2a02:8308:900c:5900:b59b:b551:1c26:2a8".parse().unwrap(),
                         port: Port(56190),
                     },
                     addr_from: Address {
                         time: (),
-                        services: 1033,
+                        services: ServiceFlags::NETWORK
+                            | ServiceFlags::WITNESS
+                            | ServiceFlags::NETWORK_LIMITED,
                         ip: "::".parse().unwrap(),
                         port: Port(0),
                     },
@@ -416,21 +893,19 @@ mod tests {
         let msg = Message {
             magic: 3652501241,
             command: Command::Version,
-            length: 102,
-            checksum: 1105356096,
             payload: Payload::Version(VersionMessage {
                 version: 70016,
-                services: 1033,
+                services: ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::NETWORK_LIMITED,
                 timestamp: 1680126222,
                 addr_recv: Address {
                     time: (),
-                    services: 0,
+                    services: ServiceFlags::NONE,
                     ip: "2a02:8308:900c:5900:b59b:b551:1c26:2a8".parse().unwrap(),
                     port: Port(56190),
                 },
                 addr_from: Address {
                     time: (),
-                    services: 1033,
+                    services: ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::NETWORK_LIMITED,
                     ip: "::192.168.0.1".parse().unwrap(),
                     port: Port(0),
                 },
@@ -445,8 +920,162 @@ mod tests {
 
         let _ = msg.encode(&mut buf).unwrap();
 
-        let decoded = Message::decode(&mut &buf[..]).unwrap();
+        // The user agent is longer than Bitcoin Core allows.
+        let error = Message::decode(&mut &buf[..]).unwrap_err();
+        assert!(error.to_string().contains("user agent"), "{}", error);
+        let mut context = Context::default();
+        context.limits.max_user_agent = 1024;
+        let decoded = Message::decode_with(&mut &buf[..], &context).unwrap();
 
         assert_eq!(decoded, msg);
+
+        // Only user agents are held to that limit.
+        let string = VariableLengthString::from("a".repeat(1000).as_str());
+        let mut buf = vec![];
+        string.encode(&mut buf).unwrap();
+        assert_eq!(VariableLengthString::decode(&mut &buf[..]).unwrap(), string);
+    }
+
+    #[test]
+    fn version_builder() {
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let peer: SocketAddr = "203.0.113.5:8333".parse().unwrap();
+        let version = VersionMessage::builder()
+            .services(ServiceFlags::NETWORK | ServiceFlags::WITNESS)
+            .addr_recv(peer)
+            .start_height(800_000)
+            .build();
+        assert_eq!(version.version, PROTOCOL_VERSION);
+        assert!(version.timestamp >= before);
+        assert_eq!(version.addr_recv.ip, peer.ip());
+        assert_eq!(version.addr_recv.port, Port(8333));
+        assert_eq!(version.addr_from.services, version.services);
+        assert_eq!(version.start_height, 800_000);
+        assert!(version.user_agent.as_str().parse::<UserAgent>().is_ok());
+        assert_ne!(version.nonce, VersionMessage::builder().build().nonce);
+
+        let fixed = VersionMessage::builder()
+            .timestamp(UNIX_EPOCH)
+            .nonce(7)
+            .user_agent("/Satoshi:25.0.0/")
+            .relay(true)
+            .build();
+        assert_eq!((fixed.timestamp, fixed.nonce, fixed.relay), (0, 7, true));
+        assert_eq!(fixed.user_agent, "/Satoshi:25.0.0/".into());
+    }
+
+    #[test]
+    fn relay_only_after_bip37() {
+        let mut version = VersionMessage {
+            version: 60002,
+            services: ServiceFlags::NETWORK,
+            timestamp: 1680126222,
+            addr_recv: Address {
+                time: (),
+                services: ServiceFlags::NONE,
+                ip: "::".parse().unwrap(),
+                port: Port(8333),
+            },
+            addr_from: Address {
+                time: (),
+                services: ServiceFlags::NETWORK,
+                ip: "::".parse().unwrap(),
+                port: Port(0),
+            },
+            nonce: 1,
+            user_agent: "/ramen/".into(),
+            start_height: 0,
+            relay: false,
+        };
+        let mut old = vec![];
+        version.encode(&mut old).unwrap();
+        assert_eq!(VersionMessage::decode(&mut &old[..]).unwrap(), version);
+
+        version.version = 70001;
+        let mut new = vec![];
+        version.encode(&mut new).unwrap();
+        assert_eq!(new.len(), old.len() + 1);
+        // Ports are big-endian.
+        assert_eq!(&new[44..46], &[0x20, 0x8d]);
+        // A missing relay flag is an error rather than a panic.
+        assert!(VersionMessage::decode(&mut &new[..new.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn checks_command_and_checksum() {
+        assert!(Message::new(0xD9B4BEF9, Command::Ping, Payload::VerAck).is_err());
+
+        let message = Message::new(0xD9B4BEF9, Command::Ping, Payload::Ping(7)).unwrap();
+        let mut buffer = BytesMut::new();
+        assert_eq!(
+            message
+                .encode_into(&mut buffer, &Context::default())
+                .unwrap(),
+            32
+        );
+        assert_eq!(Message::decode(&mut &buffer[..]).unwrap(), message);
+
+        buffer[HEADER_SIZE] ^= 1;
+        assert!(matches!(
+            Message::decode(&mut &buffer[..]),
+            Err(Error::Protocol(Violation::Checksum { .. }))
+        ));
+    }
+
+    #[test]
+    fn malformed_field_path() {
+        let message_bin = b"\xf9\xbe\xb4\xd9version\0\0\0\0\0f\0\0\0@e\xe2A\x80\x11\x01\0\t\x04\0\0\0\0\0\0\x0e\xb1$d\0\0\0\0\0\0\0\0\0\0\0\0*\x02\x83\x08\x90\x0cY\0\xb5\x9b\xb5Q\x1c&\x02\xa8\xdb~\t\x04\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0SH\x1f\xe5\xdc6S`\x10/Satoshi:23.0.0/\xe8\xf2\x0b\0\x01";
+        let mut buffer = message_bin.to_vec();
+        // User agent claims more bytes than the payload has left.
+        buffer[HEADER_SIZE + 80] = 0x7f;
+        let checksum = buffer[HEADER_SIZE..].sha256();
+        buffer[20..24].copy_from_slice(&checksum.to_le_bytes());
+
+        let error = Message::decode(&mut &buffer[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Malformed);
+        assert!(error.misbehavior() > 0);
+        let Error::Malformed { field, offset, .. } = error else {
+            panic!("expected malformed input");
+        };
+        assert_eq!(field, "version.user_agent");
+        assert_eq!(offset, Some(HEADER_SIZE + 81));
+
+        // A frame cut short is only incomplete.
+        let error = Message::decode(&mut &message_bin[..100]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Incomplete);
+        assert_eq!(error.misbehavior(), 0);
+    }
+
+    #[test]
+    fn limits_checked_before_allocation() {
+        let context = Context::default();
+        let oversize = |bytes: &[u8], command: Command| {
+            matches!(
+                Payload::decode_command(command, &mut &bytes[..], &context),
+                Err(Error::Protocol(Violation::Oversize { .. }))
+            )
+        };
+        // 50,001 inventory entries, announced without sending any.
+        assert!(oversize(&[0xfe, 0x51, 0xc3, 0, 0], Command::Inv));
+        assert!(oversize(&[0xfd, 0xd1, 0x07], Command::Headers));
+        assert!(oversize(
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            Command::Headers
+        ));
+
+        let mut version = vec![0; 80];
+        version.push(0xfd);
+        version.extend_from_slice(&257u16.to_le_bytes());
+        version.extend_from_slice(&[b'a'; 257]);
+        assert!(oversize(&version, Command::Version));
+
+        let mut codec = crate::bitcoin::BitcoinCodec::default();
+        let mut frame = BytesMut::from(&b"\xf9\xbe\xb4\xd9block\0\0\0\0\0\0\0"[..]);
+        frame.extend_from_slice(&4_000_001u32.to_le_bytes());
+        frame.extend_from_slice(&[0; 4]);
+        assert!(tokio_util::codec::Decoder::decode(&mut codec, &mut frame).is_err());
     }
 }
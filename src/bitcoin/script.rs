@@ -0,0 +1,509 @@
+use crate::bitcoin::{Context, Decode, Encode, Error, Result, VariableInt};
+use bytes::{Buf, BufMut};
+use std::fmt;
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct Opcode(pub u8);
+
+impl Opcode {
+    pub const OP_0: Opcode = Opcode(0x00);
+    pub const OP_PUSHBYTES_20: Opcode = Opcode(0x14);
+    pub const OP_PUSHBYTES_32: Opcode = Opcode(0x20);
+    pub const OP_PUSHDATA1: Opcode = Opcode(0x4c);
+    pub const OP_PUSHDATA2: Opcode = Opcode(0x4d);
+    pub const OP_PUSHDATA4: Opcode = Opcode(0x4e);
+    pub const OP_1NEGATE: Opcode = Opcode(0x4f);
+    pub const OP_1: Opcode = Opcode(0x51);
+    pub const OP_16: Opcode = Opcode(0x60);
+    pub const OP_RETURN: Opcode = Opcode(0x6a);
+    pub const OP_DUP: Opcode = Opcode(0x76);
+    pub const OP_EQUAL: Opcode = Opcode(0x87);
+    pub const OP_EQUALVERIFY: Opcode = Opcode(0x88);
+    pub const OP_HASH160: Opcode = Opcode(0xa9);
+    pub const OP_CHECKSIG: Opcode = Opcode(0xac);
+    pub const OP_CHECKMULTISIG: Opcode = Opcode(0xae);
+
+    /// The value pushed by OP_0 and OP_1 through OP_16.
+    pub fn small_int(&self) -> Option<u8> {
+        match self.0 {
+            0x00 => Some(0),
+            0x51..=0x60 => Some(self.0 - 0x50),
+            _ => None,
+        }
+    }
+
+    pub fn from_small_int(n: u8) -> Option<Opcode> {
+        match n {
+            0 => Some(Self::OP_0),
+            1..=16 => Some(Opcode(0x50 + n)),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        const SMALL_INTS: [&str; 16] = [
+            "OP_1", "OP_2", "OP_3", "OP_4", "OP_5", "OP_6", "OP_7", "OP_8", "OP_9", "OP_10",
+            "OP_11", "OP_12", "OP_13", "OP_14", "OP_15", "OP_16",
+        ];
+        const NAMES: [&str; 0xbb - 0x61] = [
+            "OP_NOP",
+            "OP_VER",
+            "OP_IF",
+            "OP_NOTIF",
+            "OP_VERIF",
+            "OP_VERNOTIF",
+            "OP_ELSE",
+            "OP_ENDIF",
+            "OP_VERIFY",
+            "OP_RETURN",
+            "OP_TOALTSTACK",
+            "OP_FROMALTSTACK",
+            "OP_2DROP",
+            "OP_2DUP",
+            "OP_3DUP",
+            "OP_2OVER",
+            "OP_2ROT",
+            "OP_2SWAP",
+            "OP_IFDUP",
+            "OP_DEPTH",
+            "OP_DROP",
+            "OP_DUP",
+            "OP_NIP",
+            "OP_OVER",
+            "OP_PICK",
+            "OP_ROLL",
+            "OP_ROT",
+            "OP_SWAP",
+            "OP_TUCK",
+            "OP_CAT",
+            "OP_SUBSTR",
+            "OP_LEFT",
+            "OP_RIGHT",
+            "OP_SIZE",
+            "OP_INVERT",
+            "OP_AND",
+            "OP_OR",
+            "OP_XOR",
+            "OP_EQUAL",
+            "OP_EQUALVERIFY",
+            "OP_RESERVED1",
+            "OP_RESERVED2",
+            "OP_1ADD",
+            "OP_1SUB",
+            "OP_2MUL",
+            "OP_2DIV",
+            "OP_NEGATE",
+            "OP_ABS",
+            "OP_NOT",
+            "OP_0NOTEQUAL",
+            "OP_ADD",
+            "OP_SUB",
+            "OP_MUL",
+            "OP_DIV",
+            "OP_MOD",
+            "OP_LSHIFT",
+            "OP_RSHIFT",
+            "OP_BOOLAND",
+            "OP_BOOLOR",
+            "OP_NUMEQUAL",
+            "OP_NUMEQUALVERIFY",
+            "OP_NUMNOTEQUAL",
+            "OP_LESSTHAN",
+            "OP_GREATERTHAN",
+            "OP_LESSTHANOREQUAL",
+            "OP_GREATERTHANOREQUAL",
+            "OP_MIN",
+            "OP_MAX",
+            "OP_WITHIN",
+            "OP_RIPEMD160",
+            "OP_SHA1",
+            "OP_SHA256",
+            "OP_HASH160",
+            "OP_HASH256",
+            "OP_CODESEPARATOR",
+            "OP_CHECKSIG",
+            "OP_CHECKSIGVERIFY",
+            "OP_CHECKMULTISIG",
+            "OP_CHECKMULTISIGVERIFY",
+            "OP_NOP1",
+            "OP_CHECKLOCKTIMEVERIFY",
+            "OP_CHECKSEQUENCEVERIFY",
+            "OP_NOP4",
+            "OP_NOP5",
+            "OP_NOP6",
+            "OP_NOP7",
+            "OP_NOP8",
+            "OP_NOP9",
+            "OP_NOP10",
+            "OP_CHECKSIGADD",
+        ];
+        match self.0 {
+            0x00 => "OP_0",
+            0x01..=0x4b => "OP_PUSHBYTES",
+            0x4c => "OP_PUSHDATA1",
+            0x4d => "OP_PUSHDATA2",
+            0x4e => "OP_PUSHDATA4",
+            0x4f => "OP_1NEGATE",
+            0x50 => "OP_RESERVED",
+            0x51..=0x60 => SMALL_INTS[(self.0 - 0x51) as usize],
+            0x61..=0xba => NAMES[(self.0 - 0x61) as usize],
+            _ => "OP_UNKNOWN",
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Debug for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(0x{:02x})", self.name(), self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Instruction<'a> {
+    /// Data pushed by opcodes 0x01 to OP_PUSHDATA4.
+    PushBytes(&'a [u8]),
+    Op(Opcode),
+}
+
+pub struct Instructions<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&opcode, rest) = self.data.split_first()?;
+        let (length, rest) = match opcode {
+            0x01..=0x4b => (opcode as usize, rest),
+            0x4c..=0x4e => {
+                let size = 1 << (opcode - 0x4c);
+                if rest.len() < size {
+                    self.data = &[];
+                    return Some(Err(Error::Parse(format!(
+                        "truncated {} length",
+                        Opcode(opcode)
+                    ))));
+                }
+                let mut length = [0; 4];
+                length[..size].copy_from_slice(&rest[..size]);
+                (u32::from_le_bytes(length) as usize, &rest[size..])
+            }
+            _ => {
+                self.data = rest;
+                return Some(Ok(Instruction::Op(Opcode(opcode))));
+            }
+        };
+        if rest.len() < length {
+            self.data = &[];
+            return Some(Err(Error::Parse(format!(
+                "push of {} bytes past end of script",
+                length
+            ))));
+        }
+        self.data = &rest[length..];
+        Some(Ok(Instruction::PushBytes(&rest[..length])))
+    }
+}
+
+/// Standard output script templates, named as Bitcoin Core reports them.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ScriptType {
+    P2pk,
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    OpReturn,
+    Multisig { required: u8, keys: u8 },
+    WitnessUnknown { version: u8 },
+    NonStandard,
+}
+
+impl fmt::Display for ScriptType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::P2pk => "pubkey",
+            Self::P2pkh => "pubkeyhash",
+            Self::P2sh => "scripthash",
+            Self::P2wpkh => "witness_v0_keyhash",
+            Self::P2wsh => "witness_v0_scripthash",
+            Self::P2tr => "witness_v1_taproot",
+            Self::OpReturn => "nulldata",
+            Self::Multisig { .. } => "multisig",
+            Self::WitnessUnknown { .. } => "witness_unknown",
+            Self::NonStandard => "nonstandard",
+        })
+    }
+}
+
+#[derive(Clone, Default, Eq, PartialEq, Hash)]
+pub struct Script(Vec<u8>);
+
+impl Script {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions { data: &self.0 }
+    }
+
+    /// Witness version and program, if this is a segwit output script.
+    pub fn witness_program(&self) -> Option<(u8, &[u8])> {
+        let (&version, rest) = self.0.split_first()?;
+        let version = Opcode(version).small_int()?;
+        let (&length, program) = rest.split_first()?;
+        if !(2..=40).contains(&length) || program.len() != length as usize {
+            return None;
+        }
+        Some((version, program))
+    }
+
+    pub fn is_push_only(&self) -> bool {
+        self.instructions().all(|instruction| match instruction {
+            Ok(Instruction::PushBytes(_)) => true,
+            Ok(Instruction::Op(op)) => op.0 <= Opcode::OP_16.0,
+            Err(_) => false,
+        })
+    }
+
+    pub fn classify(&self) -> ScriptType {
+        let bytes = &self.0[..];
+        match bytes {
+            [0x76, 0xa9, 0x14, hash @ .., 0x88, 0xac] if hash.len() == 20 => {
+                return ScriptType::P2pkh
+            }
+            [0xa9, 0x14, hash @ .., 0x87] if hash.len() == 20 => return ScriptType::P2sh,
+            [0x6a, ..] => {
+                return match Script(bytes[1..].to_vec()).is_push_only() {
+                    true => ScriptType::OpReturn,
+                    false => ScriptType::NonStandard,
+                }
+            }
+            _ => {}
+        }
+        if let Some((version, program)) = self.witness_program() {
+            return match (version, program.len()) {
+                (0, 20) => ScriptType::P2wpkh,
+                (0, 32) => ScriptType::P2wsh,
+                (1, 32) => ScriptType::P2tr,
+                (0, _) => ScriptType::NonStandard,
+                (version, _) => ScriptType::WitnessUnknown { version },
+            };
+        }
+
+        let Ok(instructions) = self.instructions().collect::<Result<Vec<_>>>() else {
+            return ScriptType::NonStandard;
+        };
+        let is_pubkey = |data: &[u8]| matches!(data.len(), 33 | 65);
+        match &instructions[..] {
+            [Instruction::PushBytes(key), Instruction::Op(Opcode::OP_CHECKSIG)]
+                if is_pubkey(key) =>
+            {
+                ScriptType::P2pk
+            }
+            [Instruction::Op(m), keys @ .., Instruction::Op(n), Instruction::Op(Opcode::OP_CHECKMULTISIG)] => {
+                match (m.small_int(), n.small_int()) {
+                    (Some(required @ 1..), Some(total))
+                        if required <= total
+                            && total as usize == keys.len()
+                            && keys.iter().all(
+                                |key| matches!(key, Instruction::PushBytes(k) if is_pubkey(k)),
+                            ) =>
+                    {
+                        ScriptType::Multisig {
+                            required,
+                            keys: total,
+                        }
+                    }
+                    _ => ScriptType::NonStandard,
+                }
+            }
+            _ => ScriptType::NonStandard,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Script {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<&[u8]> for Script {
+    fn from(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec())
+    }
+}
+
+impl AsRef<[u8]> for Script {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Script({})", hex::encode(&self.0))
+    }
+}
+
+/// Disassembles the script the way Bitcoin Core's `asm` fields do: small
+/// pushes as numbers, larger pushes as hex and everything else by name.
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, instruction) in self.instructions().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            match instruction {
+                Ok(Instruction::PushBytes(data)) if data.len() <= 4 => {
+                    write!(f, "{}", script_num(data))?
+                }
+                Ok(Instruction::PushBytes(data)) => f.write_str(&hex::encode(data))?,
+                Ok(Instruction::Op(Opcode::OP_1NEGATE)) => f.write_str("-1")?,
+                Ok(Instruction::Op(op)) => match op.small_int() {
+                    Some(n) => write!(f, "{}", n)?,
+                    None => f.write_str(op.name())?,
+                },
+                Err(_) => return f.write_str("[error]"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Interprets up to four bytes as a little-endian sign-magnitude number.
+fn script_num(data: &[u8]) -> i64 {
+    let Some((&last, _)) = data.split_last() else {
+        return 0;
+    };
+    let mut value = 0i64;
+    for (i, byte) in data.iter().enumerate() {
+        value |= (*byte as i64) << (8 * i);
+    }
+    if last & 0x80 != 0 {
+        -(value & !(0x80i64 << (8 * (data.len() - 1))))
+    } else {
+        value
+    }
+}
+
+impl Encode for Script {
+    fn encode_with(&self, buffer: &mut impl BufMut, context: &Context) -> Result<usize> {
+        let written = VariableInt(self.0.len() as u64).encode_with(buffer, context)?;
+        if buffer.remaining_mut() < self.0.len() {
+            return Err(Error::NotEnoughSpace("script"));
+        }
+        buffer.put_slice(&self.0);
+        Ok(written + self.0.len())
+    }
+}
+
+impl Decode for Script {
+    fn decode_with(bytes: &mut impl Buf, context: &Context) -> Result<Self> {
+        let length = VariableInt::decode_with(bytes, context)?.0 as usize;
+        if bytes.remaining() < length {
+            return Err(Error::NotEnoughBytes("script"));
+        }
+        let mut script = vec![0; length];
+        bytes.copy_to_slice(&mut script);
+        Ok(Self(script))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn script(hex: &str) -> Script {
+        Script(hex::decode(hex).unwrap())
+    }
+
+    #[test]
+    fn classify() {
+        let key = "02".to_string() + &"11".repeat(32);
+        let cases = [
+            (
+                "76a914".to_string() + &"ab".repeat(20) + "88ac",
+                ScriptType::P2pkh,
+            ),
+            (
+                "a914".to_string() + &"ab".repeat(20) + "87",
+                ScriptType::P2sh,
+            ),
+            ("0014".to_string() + &"ab".repeat(20), ScriptType::P2wpkh),
+            ("0020".to_string() + &"ab".repeat(32), ScriptType::P2wsh),
+            ("5120".to_string() + &"ab".repeat(32), ScriptType::P2tr),
+            (
+                "5202".to_string() + &"ab".repeat(2),
+                ScriptType::WitnessUnknown { version: 2 },
+            ),
+            ("21".to_string() + &key + "ac", ScriptType::P2pk),
+            ("6a0568656c6c6f".to_string(), ScriptType::OpReturn),
+            ("6a".to_string(), ScriptType::OpReturn),
+            (
+                format!("5121{}21{}52ae", key, key),
+                ScriptType::Multisig {
+                    required: 1,
+                    keys: 2,
+                },
+            ),
+            (format!("5321{}21{}52ae", key, key), ScriptType::NonStandard),
+            ("76a9".to_string(), ScriptType::NonStandard),
+            ("4c".to_string(), ScriptType::NonStandard),
+        ];
+        for (hex, expected) in cases {
+            assert_eq!(script(&hex).classify(), expected, "{}", hex);
+        }
+    }
+
+    #[test]
+    fn asm() {
+        let p2pkh = script("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac");
+        assert_eq!(
+            p2pkh.to_string(),
+            "OP_DUP OP_HASH160 62e907b15cbf27d5425399ebf6f0fb50ebb88f18 OP_EQUALVERIFY OP_CHECKSIG"
+        );
+        assert_eq!(script("004f5160029000").to_string(), "0 -1 1 16 144");
+        assert_eq!(script("0181").to_string(), "-1");
+        assert_eq!(
+            script("6a4c0568656c6c6f").to_string(),
+            "OP_RETURN 68656c6c6f"
+        );
+        assert_eq!(script("4d0500").to_string(), "[error]");
+        assert_eq!(script("ba").to_string(), "OP_CHECKSIGADD");
+        assert_eq!(script("ff").to_string(), "OP_UNKNOWN");
+    }
+
+    #[test]
+    fn instructions() {
+        let script = script("4c02abcd4d0100ef76");
+        let instructions = script.instructions().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::PushBytes(&[0xab, 0xcd]),
+                Instruction::PushBytes(&[0xef]),
+                Instruction::Op(Opcode::OP_DUP),
+            ]
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Serde support, behind the `serde` feature. Most types derive it where
+//! they're defined; the ones whose wire layout makes for poor JSON are
+//! implemented here. Hashes are reversed hex like txids are usually shown,
+//! scripts and witness items plain hex, and human-unreadable formats get
+//! raw bytes instead.
+
+use crate::bitcoin::{Block, Hash256, LazyBlock, Message, Payload, Script, VariableLengthString};
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+impl Serialize for Hash256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            self.as_bytes().serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            String::deserialize(deserializer)?
+                .parse()
+                .map_err(D::Error::custom)
+        } else {
+            <[u8; 32]>::deserialize(deserializer).map(Self::from)
+        }
+    }
+}
+
+impl Serialize for Script {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        hex_bytes::serialize(self.as_bytes(), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Script {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        hex_bytes::deserialize(deserializer).map(Self::from)
+    }
+}
+
+impl Serialize for VariableLengthString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for VariableLengthString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(String::deserialize(deserializer)?.as_str().into())
+    }
+}
+
+/// Serialized as the `Block` it stands for, so its transactions are parsed.
+impl Serialize for LazyBlock {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_block()
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LazyBlock {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self::from(&Block::deserialize(deserializer)?))
+    }
+}
+
+/// A message is its magic next to the payload's `command` and `payload`
+/// fields; the command isn't stored twice.
+#[derive(Serialize, Deserialize)]
+struct MessageFields<P> {
+    magic: u32,
+    #[serde(flatten)]
+    payload: P,
+}
+
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        MessageFields {
+            magic: self.magic(),
+            payload: self.payload(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fields = MessageFields::<Payload>::deserialize(deserializer)?;
+        let command = fields.payload.command();
+        Message::new(fields.magic, command, fields.payload).map_err(D::Error::custom)
+    }
+}
+
+/// Bytes as hex in human-readable formats.
+pub(crate) mod hex_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex::encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            hex::decode(String::deserialize(deserializer)?).map_err(D::Error::custom)
+        } else {
+            Vec::deserialize(deserializer)
+        }
+    }
+}
+
+/// A list of byte strings, like a witness stack, each as in `hex_bytes`.
+pub(crate) mod hex_items {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Item(#[serde(with = "hex_bytes")] Vec<u8>);
+
+    pub fn serialize<S: Serializer>(items: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(items.iter().map(|item| Item(item.clone())))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<u8>>, D::Error> {
+        let items = Vec::<Item>::deserialize(deserializer)?;
+        Ok(items.into_iter().map(|item| item.0).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::{
+        Address, Command, Inventory, InventoryKind, Network, OutPoint, ServiceFlags, Transaction,
+        TxIn, TxOut, VersionMessage,
+    };
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn round_trip(message: &Message) -> serde_json::Value {
+        let value = serde_json::to_value(message).unwrap();
+        let back: Message = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(&back, message);
+        value
+    }
+
+    #[test]
+    fn version() {
+        let magic = Network::Mainnet.magic();
+        let version = VersionMessage {
+            version: 70016,
+            services: ServiceFlags::from(1033),
+            timestamp: 1680126222,
+            addr_recv: Address {
+                time: (),
+                services: ServiceFlags::NONE,
+                ip: "2a02:8308:900c:5900:b59b:b551:1c26:2a8".parse().unwrap(),
+                port: 56190.into(),
+            },
+            addr_from: Address {
+                time: (),
+                services: ServiceFlags::from(1033),
+                ip: "203.0.113.5".parse().unwrap(),
+                port: 8333.into(),
+            },
+            nonce: 6940951773072803923,
+            user_agent: "/Satoshi:23.0.0/".into(),
+            start_height: 783080,
+            relay: true,
+        };
+        let message = Message::new(magic, Command::Version, Payload::Version(version)).unwrap();
+        assert_eq!(
+            round_trip(&message),
+            json!({
+                "magic": magic,
+                "command": "version",
+                "payload": {
+                    "version": 70016,
+                    "services": 1033,
+                    "timestamp": 1680126222,
+                    "addr_recv": {
+                        "time": null,
+                        "services": 0,
+                        "ip": "2a02:8308:900c:5900:b59b:b551:1c26:2a8",
+                        "port": 56190,
+                    },
+                    "addr_from": {
+                        "time": null,
+                        "services": 1033,
+                        "ip": "203.0.113.5",
+                        "port": 8333,
+                    },
+                    "nonce": 6940951773072803923u64,
+                    "user_agent": "/Satoshi:23.0.0/",
+                    "start_height": 783080,
+                    "relay": true,
+                },
+            })
+        );
+
+        let verack = Message::new(magic, Command::VerAck, Payload::VerAck).unwrap();
+        assert_eq!(
+            round_trip(&verack),
+            json!({ "magic": magic, "command": "verack" })
+        );
+    }
+
+    #[test]
+    fn hashes_and_transactions() {
+        let magic = Network::Regtest.magic();
+        let hash: Hash256 = "00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054"
+            .parse()
+            .unwrap();
+        let inv = Message::new(
+            magic,
+            Command::Inv,
+            Payload::Inv(vec![Inventory {
+                kind: InventoryKind::Unknown(7),
+                hash,
+            }]),
+        )
+        .unwrap();
+        assert_eq!(
+            round_trip(&inv)["payload"],
+            json!([{ "kind": { "unknown": 7 }, "hash": hash.to_string() }])
+        );
+
+        let tx = Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: hash,
+                    vout: 1,
+                },
+                script_sig: Script::from(vec![]),
+                sequence: 0xfffffffd,
+                witness: vec![vec![0xde, 0xad], vec![]],
+            }],
+            outputs: vec![TxOut {
+                value: 50_000,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+            lock_time: 0,
+        };
+        let message = Message::new(magic, Command::Tx, Payload::Tx(tx)).unwrap();
+        let input = &round_trip(&message)["payload"]["inputs"][0];
+        assert_eq!(input["previous_output"]["txid"], hash.to_string());
+        assert_eq!(input["witness"], json!(["dead", ""]));
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        let result = serde_json::from_value::<Message>(json!({ "magic": 0, "command": "wtfmsg" }));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::bitcoin::{Decode, Encode};
+use std::fmt;
+use std::ops::{BitAnd, BitOr, BitOrAssign};
+
+/// Services a node offers, as advertised in `version` and `addr` messages.
+/// Bits we have no name for are kept as they are, so flags survive being
+/// decoded and encoded again.
+#[derive(Clone, Copy, Default, Eq, PartialEq, Hash, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ServiceFlags(u64);
+
+impl ServiceFlags {
+    pub const NONE: Self = Self(0);
+    /// NODE_NETWORK: serves the full block chain.
+    pub const NETWORK: Self = Self(1 << 0);
+    /// NODE_BLOOM: supports bloom filters and `mempool` (BIP111).
+    pub const BLOOM: Self = Self(1 << 2);
+    /// NODE_WITNESS: relays witness data (BIP144).
+    pub const WITNESS: Self = Self(1 << 3);
+    /// NODE_COMPACT_FILTERS: serves compact block filters (BIP157).
+    pub const COMPACT_FILTERS: Self = Self(1 << 6);
+    /// NODE_NETWORK_LIMITED: serves the last 288 blocks (BIP159).
+    pub const NETWORK_LIMITED: Self = Self(1 << 10);
+    /// NODE_P2P_V2: speaks the encrypted transport (BIP324).
+    pub const P2P_V2: Self = Self(1 << 11);
+
+    /// Names as Bitcoin Core shows them, in bit order.
+    const NAMES: [(Self, &'static str); 6] = [
+        (Self::NETWORK, "NETWORK"),
+        (Self::BLOOM, "BLOOM"),
+        (Self::WITNESS, "WITNESS"),
+        (Self::COMPACT_FILTERS, "COMPACT_FILTERS"),
+        (Self::NETWORK_LIMITED, "NETWORK_LIMITED"),
+        (Self::P2P_V2, "P2P_V2"),
+    ];
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether every flag in `other` is set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+
+    /// Names of the flags set, with bits we don't know as `UNKNOWN[2^n]`.
+    pub fn names(self) -> Vec<String> {
+        (0..64)
+            .map(|bit| Self(1 << bit))
+            .filter(|flag| self.contains(*flag))
+            .map(|flag| {
+                let known = Self::NAMES.iter().find(|(known, _)| *known == flag);
+                match known {
+                    Some((_, name)) => name.to_string(),
+                    None => format!("UNKNOWN[2^{}]", flag.0.trailing_zeros()),
+                }
+            })
+            .collect()
+    }
+}
+
+impl From<u64> for ServiceFlags {
+    fn from(bits: u64) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<ServiceFlags> for u64 {
+    fn from(flags: ServiceFlags) -> Self {
+        flags.0
+    }
+}
+
+impl BitOr for ServiceFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for ServiceFlags {
+    fn bitor_assign(&mut self, other: Self) {
+        self.insert(other);
+    }
+}
+
+impl BitAnd for ServiceFlags {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// Comma-separated names like Core's `getpeerinfo`, or `NONE`.
+impl fmt::Display for ServiceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("NONE");
+        }
+        f.write_str(&self.names().join(", "))
+    }
+}
+
+impl fmt::Debug for ServiceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ServiceFlags({:#x}: {})", self.0, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn names_and_unknown_bits() {
+        let flags = ServiceFlags::from(1033);
+        assert_eq!(
+            flags,
+            ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::NETWORK_LIMITED
+        );
+        assert_eq!(flags.to_string(), "NETWORK, WITNESS, NETWORK_LIMITED");
+        assert!(flags.contains(ServiceFlags::NETWORK | ServiceFlags::WITNESS));
+        assert!(!flags.contains(ServiceFlags::BLOOM));
+        assert_eq!(ServiceFlags::NONE.to_string(), "NONE");
+
+        let mut flags = ServiceFlags::from(1 << 1 | 1 << 3 | 1 << 63);
+        assert_eq!(flags.to_string(), "UNKNOWN[2^1], WITNESS, UNKNOWN[2^63]");
+        flags.remove(ServiceFlags::WITNESS);
+        flags |= ServiceFlags::P2P_V2;
+        assert_eq!(flags.bits(), 1 << 1 | 1 << 11 | 1 << 63);
+
+        let mut buffer = vec![];
+        flags.encode(&mut buffer).unwrap();
+        assert_eq!(ServiceFlags::decode(&mut &buffer[..]).unwrap(), flags);
+    }
+}
@@ -0,0 +1,233 @@
+use crate::bitcoin::{
+    Context, Decode, Encode, Error, Hash256, Result, Script, Txid, VariableInt, Wtxid,
+};
+use bytes::{Buf, BufMut};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Transaction {
+    pub version: i32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+    pub lock_time: u32,
+}
+
+impl Transaction {
+    pub fn txid(&self) -> Txid {
+        let mut buffer = Vec::new();
+        self.encode_legacy(&mut buffer, &Context::default())
+            .expect("encoding into a vec cannot fail");
+        Hash256::hash(&buffer)
+    }
+
+    pub fn wtxid(&self) -> Wtxid {
+        let mut buffer = Vec::new();
+        self.encode(&mut buffer)
+            .expect("encoding into a vec cannot fail");
+        Hash256::hash(&buffer)
+    }
+
+    pub fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|input| !input.witness.is_empty())
+    }
+
+    /// Length of the transaction serialized at the start of `bytes`, found by
+    /// skipping over it without decoding anything.
+    pub(crate) fn measure(bytes: &[u8], context: &Context) -> Result<usize> {
+        let mut cursor = bytes;
+        skip(&mut cursor, 4)?;
+        let segwit = context.witness && cursor.starts_with(&[0x00, 0x01]);
+        if segwit {
+            cursor.advance(2);
+        }
+        let inputs = VariableInt::decode_with(&mut cursor, context)?.0;
+        for _ in 0..inputs {
+            skip(&mut cursor, 36)?;
+            skip_prefixed(&mut cursor, context)?;
+            skip(&mut cursor, 4)?;
+        }
+        let outputs = VariableInt::decode_with(&mut cursor, context)?.0;
+        for _ in 0..outputs {
+            skip(&mut cursor, 8)?;
+            skip_prefixed(&mut cursor, context)?;
+        }
+        if segwit {
+            for _ in 0..inputs {
+                let items = VariableInt::decode_with(&mut cursor, context)?.0;
+                for _ in 0..items {
+                    skip_prefixed(&mut cursor, context)?;
+                }
+            }
+        }
+        skip(&mut cursor, 4)?;
+        Ok(bytes.len() - cursor.len())
+    }
+
+    fn encode_legacy(&self, buffer: &mut impl BufMut, context: &Context) -> Result<usize> {
+        let mut written = self.version.encode_with(buffer, context)?;
+        written += self.inputs.encode_with(buffer, context)?;
+        written += self.outputs.encode_with(buffer, context)?;
+        written += self.lock_time.encode_with(buffer, context)?;
+        Ok(written)
+    }
+}
+
+fn skip(cursor: &mut &[u8], length: usize) -> Result<()> {
+    if cursor.len() < length {
+        return Err(Error::NotEnoughBytes("transaction"));
+    }
+    cursor.advance(length);
+    Ok(())
+}
+
+/// Skips a varint length followed by that many bytes.
+fn skip_prefixed(cursor: &mut &[u8], context: &Context) -> Result<()> {
+    let length = VariableInt::decode_with(cursor, context)?.0;
+    skip(cursor, usize::try_from(length).unwrap_or(usize::MAX))
+}
+
+impl Encode for Transaction {
+    fn encode_with(&self, buffer: &mut impl BufMut, context: &Context) -> Result<usize> {
+        if !context.witness || !self.has_witness() {
+            return self.encode_legacy(buffer, context);
+        }
+        let mut written = self.version.encode_with(buffer, context)?;
+        written += 0u8.encode_with(buffer, context)?;
+        written += 1u8.encode_with(buffer, context)?;
+        written += self.inputs.encode_with(buffer, context)?;
+        written += self.outputs.encode_with(buffer, context)?;
+        for input in &self.inputs {
+            written += input.witness.encode_with(buffer, context)?;
+        }
+        written += self.lock_time.encode_with(buffer, context)?;
+        Ok(written)
+    }
+}
+
+impl Decode for Transaction {
+    fn decode_with(bytes: &mut impl Buf, context: &Context) -> Result<Self> {
+        let version = i32::decode_with(bytes, context).map_err(|e| e.in_field("version"))?;
+        let segwit = context.witness && bytes.chunk().starts_with(&[0x00, 0x01]);
+        if segwit {
+            bytes.advance(2);
+        }
+        let mut inputs =
+            Vec::<TxIn>::decode_with(bytes, context).map_err(|e| e.in_field("inputs"))?;
+        let outputs =
+            Vec::<TxOut>::decode_with(bytes, context).map_err(|e| e.in_field("outputs"))?;
+        if segwit {
+            for (index, input) in inputs.iter_mut().enumerate() {
+                input.witness = Vec::decode_with(bytes, context)
+                    .map_err(|e| e.in_field(&format!("inputs.{}.witness", index)))?;
+            }
+            if inputs.iter().all(|input| input.witness.is_empty()) {
+                return Err(Error::malformed("superfluous witness flag"));
+            }
+        }
+        let lock_time = u32::decode_with(bytes, context).map_err(|e| e.in_field("lock_time"))?;
+        Ok(Transaction {
+            version,
+            inputs,
+            outputs,
+            lock_time,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TxIn {
+    pub previous_output: OutPoint,
+    pub script_sig: Script,
+    pub sequence: u32,
+    /// Witness stack, serialized separately from the input itself.
+    #[wire(skip)]
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::bitcoin::serialize::hex_items")
+    )]
+    pub witness: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TxOut {
+    pub value: i64,
+    pub script_pubkey: Script,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OutPoint {
+    pub txid: Txid,
+    pub vout: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn decode_legacy() {
+        // The first bitcoin transaction between two people, from block 170.
+        let raw = from_hex("0100000001c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce25857fcd3704000000004847304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901ffffffff0200ca9a3b00000000434104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac00286bee0000000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000");
+        let tx = Transaction::decode(&mut &raw[..]).unwrap();
+        assert_eq!(tx.inputs.len(), 1);
+        assert_eq!(tx.outputs[0].value, 1_000_000_000);
+        assert_eq!(
+            tx.txid().to_string(),
+            "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16"
+        );
+        assert_eq!(tx.txid(), tx.wtxid());
+
+        let mut buffer = vec![];
+        tx.encode(&mut buffer).unwrap();
+        assert_eq!(buffer, raw);
+    }
+
+    #[test]
+    fn encode_decode_witness() {
+        let tx = Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Hash256::hash(b"previous"),
+                    vout: 1,
+                },
+                script_sig: Script::default(),
+                sequence: 0xFFFFFFFD,
+                witness: vec![vec![0x30; 71], vec![0x02; 33]],
+            }],
+            outputs: vec![TxOut {
+                value: 50_000,
+                script_pubkey: [&[0x00, 0x14][..], &[0xAB; 20]].concat().into(),
+            }],
+            lock_time: 0,
+        };
+
+        let mut buffer = vec![];
+        tx.encode(&mut buffer).unwrap();
+        assert_eq!(&buffer[4..6], &[0x00, 0x01]);
+
+        let decoded = Transaction::decode(&mut &buffer[..]).unwrap();
+        assert_eq!(decoded, tx);
+        assert_ne!(decoded.txid(), decoded.wtxid());
+
+        // Peers which don't relay witness data get the legacy serialization.
+        let legacy = Context {
+            witness: false,
+            ..Context::default()
+        };
+        let mut buffer = vec![];
+        tx.encode_with(&mut buffer, &legacy).unwrap();
+        assert_eq!(Hash256::hash(&buffer), tx.txid());
+    }
+}
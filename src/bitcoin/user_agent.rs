@@ -0,0 +1,239 @@
+//! User agents as laid out in BIP14: components like `/Name:Version/`, the
+//! underlying library first and the application last, each optionally
+//! followed by comments in parentheses, `/Name:Version(comment; comment)/`.
+
+use crate::bitcoin::{Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Longest user agent Bitcoin Core accepts.
+pub const MAX_USER_AGENT_LENGTH: usize = 256;
+
+/// One `Name:Version(comments)` part of a user agent.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Component {
+    pub name: String,
+    pub version: String,
+    pub comments: Vec<String>,
+}
+
+impl Component {
+    pub fn new(name: impl Into<String>, version: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            version: version.into(),
+            comments: vec![],
+        }
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comments.push(comment.into());
+        self
+    }
+
+    /// This library's own component, like `handshake:0.1.0`.
+    pub fn handshake() -> Self {
+        Self::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (what, value) in [("name", &self.name), ("version", &self.version)] {
+            if value.is_empty() {
+                return Err(invalid(format!("empty {}", what)));
+            }
+            if let Some(c) = value.chars().find(|c| !is_name_char(*c)) {
+                return Err(invalid(format!("{:?} in {} {:?}", c, what, value)));
+            }
+        }
+        for comment in &self.comments {
+            if let Some(c) = comment.chars().find(|c| !is_comment_char(*c)) {
+                return Err(invalid(format!("{:?} in comment {:?}", c, comment)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Component {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, self.version)?;
+        if !self.comments.is_empty() {
+            write!(f, "({})", self.comments.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A user agent made of BIP14 components. Building one doesn't check it;
+/// `validate`, parsing and `to_string_checked` do.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct UserAgent {
+    pub components: Vec<Component>,
+}
+
+impl UserAgent {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, component: Component) -> Self {
+        self.components.push(component);
+        self
+    }
+
+    /// The application, which BIP14 puts last, like `btcd` in
+    /// `/btcwire:0.5.0/btcd:0.23.3/`.
+    pub fn implementation(&self) -> Option<&Component> {
+        self.components.last()
+    }
+
+    /// Checks that there's at least one component, names, versions and
+    /// comments use only the characters Bitcoin Core allows, and the whole
+    /// fits in `MAX_USER_AGENT_LENGTH`.
+    pub fn validate(&self) -> Result<()> {
+        if self.components.is_empty() {
+            return Err(invalid("no components".to_string()));
+        }
+        for component in &self.components {
+            component.validate()?;
+        }
+        let length = self.to_string().len();
+        if length > MAX_USER_AGENT_LENGTH {
+            return Err(invalid(format!(
+                "{} bytes long, over {}",
+                length, MAX_USER_AGENT_LENGTH
+            )));
+        }
+        Ok(())
+    }
+
+    /// The user agent as sent, if it's valid.
+    pub fn to_string_checked(&self) -> Result<String> {
+        self.validate()?;
+        Ok(self.to_string())
+    }
+}
+
+impl fmt::Display for UserAgent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("/")?;
+        for component in &self.components {
+            write!(f, "{}/", component)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for UserAgent {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() > MAX_USER_AGENT_LENGTH {
+            return Err(invalid(format!(
+                "{} bytes long, over {}",
+                s.len(),
+                MAX_USER_AGENT_LENGTH
+            )));
+        }
+        let inner = s
+            .strip_prefix('/')
+            .and_then(|s| s.strip_suffix('/'))
+            .ok_or_else(|| invalid(format!("{:?} isn't enclosed in slashes", s)))?;
+        let mut user_agent = UserAgent::new();
+        // Comments can't contain slashes, so splitting on them is safe.
+        for part in inner.split('/') {
+            let (head, comments) = match part.split_once('(') {
+                Some((head, rest)) => {
+                    let comments = rest
+                        .strip_suffix(')')
+                        .ok_or_else(|| invalid(format!("unclosed comment in {:?}", part)))?;
+                    (
+                        head,
+                        comments.split(';').map(|c| c.trim().to_string()).collect(),
+                    )
+                }
+                None => (part, vec![]),
+            };
+            let (name, version) = head
+                .split_once(':')
+                .ok_or_else(|| invalid(format!("no version in {:?}", part)))?;
+            user_agent.components.push(Component {
+                comments,
+                ..Component::new(name, version)
+            });
+        }
+        user_agent.validate()?;
+        Ok(user_agent)
+    }
+}
+
+fn invalid(reason: String) -> Error {
+    Error::Parse(format!("invalid user agent: {}", reason))
+}
+
+/// Bitcoin Core's SAFE_CHARS_DEFAULT, less the separators.
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || " .,;-_?@".contains(c)
+}
+
+/// Bitcoin Core's SAFE_CHARS_UA_COMMENT, less the `;` between comments.
+fn is_comment_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || " .,-_?@".contains(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse() {
+        let user_agent: UserAgent = "/Satoshi:0.21.0(bitcoin fees; 2.1)/Knots:20210130/"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            user_agent.components,
+            vec![
+                Component::new("Satoshi", "0.21.0")
+                    .comment("bitcoin fees")
+                    .comment("2.1"),
+                Component::new("Knots", "20210130"),
+            ]
+        );
+        assert_eq!(user_agent.implementation().unwrap().name, "Knots");
+        assert_eq!(
+            user_agent.to_string(),
+            "/Satoshi:0.21.0(bitcoin fees; 2.1)/Knots:20210130/"
+        );
+
+        for bad in [
+            "Satoshi:23.0.0",
+            "//",
+            "/Satoshi/",
+            "/Satoshi:23.0.0(unclosed/",
+            "/:23.0.0/",
+            "/Sat\noshi:23.0.0/",
+        ] {
+            assert!(bad.parse::<UserAgent>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn build() {
+        let user_agent = UserAgent::new()
+            .with(Component::new("ourapp", "1.2"))
+            .with(Component::new("handshake", "0.1"));
+        assert_eq!(
+            user_agent.to_string_checked().unwrap(),
+            "/ourapp:1.2/handshake:0.1/"
+        );
+
+        let bad = UserAgent::new().with(Component::new("our/app", "1.2"));
+        assert!(bad.to_string_checked().is_err());
+        let bad = UserAgent::new().with(Component::new("app", "1").comment("(nested)"));
+        assert!(bad.validate().is_err());
+        let long = UserAgent::new().with(Component::new("a".repeat(256), "1"));
+        assert!(long.validate().is_err());
+        assert!(UserAgent::new().validate().is_err());
+    }
+}
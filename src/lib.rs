@@ -0,0 +1,7 @@
+// Lets the derive macros refer to `::handshake` from inside this crate too.
+extern crate self as handshake;
+
+pub mod bitcoin;
+
+#[doc(hidden)]
+pub use bytes as __bytes;
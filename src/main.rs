@@ -1,81 +1,754 @@
-mod bitcoin;
+use clap::{Parser, Subcommand};
+use futures::StreamExt;
+use handshake::bitcoin::ban::{BanList, BanManager, BanOptions};
+use handshake::bitcoin::crawl::{CrawlOptions, NodeRecord};
+use handshake::bitcoin::headers::{sync_headers, HeaderChain};
+use handshake::bitcoin::peer::{Peer, PeerConfig, Transport};
+use handshake::bitcoin::user_agent::{Component, UserAgent};
+use handshake::bitcoin::{
+    Checksum, Context, Decode, Encode, Message, Network, Payload, VersionMessage, Violation,
+    HEADER_SIZE,
+};
+use serde_json::json;
+use std::error::Error;
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_socks::tcp::Socks5Stream;
 
-use bitcoin::{Address, Command, Message, Payload, VersionMessage};
-use futures::{SinkExt, StreamExt};
-use tokio::net::TcpStream;
-use tokio_util::codec::Framed;
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+/// A peer over TCP, directly or through a proxy.
+type Connection = Peer<Box<dyn Transport>>;
+
+/// Talks to Bitcoin nodes over the peer-to-peer protocol.
+#[derive(Debug, Clone, Parser)]
+#[command(version)]
+struct Cli {
+    /// mainnet, testnet, signet or regtest.
+    #[arg(long, global = true, default_value_t = Network::Mainnet)]
+    network: Network,
+    /// Application to announce as name:version, ahead of this library in the
+    /// user agent, like /app:1.0/handshake:0.1.0/.
+    #[arg(long, global = true, value_parser = user_agent)]
+    user_agent: Option<String>,
+    /// Seconds to wait for a connection or a reply.
+    #[arg(long, global = true, default_value_t = 10)]
+    timeout: u64,
+    /// SOCKS5 proxy to connect through, like 127.0.0.1:9050 for Tor.
+    #[arg(long, global = true)]
+    proxy: Option<String>,
+    /// Print JSON, one object per line, instead of text.
+    #[arg(long, global = true)]
+    json: bool,
+    /// Records every message exchanged under this directory, a directory
+    /// per peer, like Bitcoin Core's -capturemessages.
+    #[arg(long, global = true)]
+    capture: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Connects and shows what the node says about itself.
+    Handshake {
+        /// Host or IP, with the network's port unless one is given.
+        addr: String,
+    },
+    /// Measures the round trip time to a node.
+    Ping {
+        addr: String,
+        #[arg(long, default_value_t = 3)]
+        count: u32,
+    },
+    /// Asks a node for addresses of other nodes.
+    #[command(name = "getaddr")]
+    GetAddr { addr: String },
+    /// Downloads the header chain from a node.
+    Headers { addr: String },
+    /// Explores the network from the DNS seeds (or the given nodes) through
+    /// the addresses each node returns, printing a CSV line per node.
+    Crawl {
+        seeds: Vec<String>,
+        /// Nodes visited at once.
+        #[arg(long, default_value_t = 64)]
+        concurrency: usize,
+        /// Stop after visiting this many nodes.
+        #[arg(long)]
+        max_nodes: Option<usize>,
+        /// Writes the records to this file instead of stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Accepts connections and shows who connects and what they send.
+    /// Misbehaving peers are disconnected and banned for a day.
+    Listen {
+        /// Defaults to all interfaces on the network's port.
+        bind: Option<SocketAddr>,
+        /// Keeps bans in this file, so they outlast the process.
+        #[arg(long)]
+        banlist: Option<PathBuf>,
+    },
+    /// Shows the framed messages in a hex string or a binary file.
+    Decode {
+        /// Hex, or the path of a file holding raw frames.
+        input: String,
+    },
+    /// Frames messages described in JSON, as `decode --json` prints them.
+    Encode {
+        /// A JSON object or array of objects, or the path of a file holding
+        /// one. Each needs a command and a payload; the magic defaults to the
+        /// network's.
+        input: String,
+        /// Writes the raw frames to this file instead of printing hex.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Stays connected to a node and shows everything it relays.
+    Monitor { addr: String },
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Connecting");
-
-    let stream = TcpStream::connect("seed.bitcoin.sipa.be:8333").await?;
-    let framed_stream = Framed::new(stream, bitcoin::BitcoinCodec).fuse();
-    let (mut sink, mut stream) = framed_stream.split();
-
-    println!("Connected");
-
-    let version_message = VersionMessage {
-        version: 70015,
-        timestamp: 0,
-        user_agent: "/ramen/".into(),
-        addr_recv: Address {
-            time: (),
-            services: 0,
-            ip: "::".parse().unwrap(),
-            port: 0.into(),
-        },
-        addr_from: Address {
-            time: (),
-            services: 0,
-            ip: "::".parse().unwrap(),
-            port: 0.into(),
-        },
-        nonce: 0,
-        services: 0,
-        start_height: 0,
-        relay: false,
-    };
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    match &cli.command {
+        Command::Handshake { addr } => handshake(&cli, addr).await,
+        Command::Ping { addr, count } => ping(&cli, addr, *count).await,
+        Command::GetAddr { addr } => getaddr(&cli, addr).await,
+        Command::Headers { addr } => headers(&cli, addr).await,
+        Command::Crawl {
+            seeds,
+            concurrency,
+            max_nodes,
+            output,
+        } => {
+            let options = CrawlOptions {
+                concurrency: *concurrency,
+                max_nodes: max_nodes.unwrap_or(usize::MAX),
+                connect_timeout: Duration::from_secs(cli.timeout),
+                addr_timeout: Duration::from_secs(cli.timeout),
+            };
+            crawl(&cli, seeds, options, output.as_deref()).await
+        }
+        Command::Listen { bind, banlist } => listen(&cli, *bind, banlist.as_deref()).await,
+        Command::Decode { input } => decode(&cli, input),
+        Command::Encode { input, output } => encode(&cli, input, output.as_deref()),
+        Command::Monitor { addr } => monitor(&cli, addr).await,
+    }
+}
+
+/// `/name:version/handshake:x.y.z/` from `name:version`, checked against
+/// BIP14.
+fn user_agent(app: &str) -> Result<String> {
+    let (name, version) = app
+        .split_once(':')
+        .ok_or_else(|| format!("expected name:version, got {:?}", app))?;
+    let user_agent = UserAgent::new()
+        .with(Component::new(name, version))
+        .with(Component::handshake());
+    Ok(user_agent.to_string_checked()?)
+}
+
+impl Cli {
+    fn config(&self) -> PeerConfig {
+        let mut config = PeerConfig {
+            network: self.network,
+            capture: self.capture.clone(),
+            ..PeerConfig::default()
+        };
+        if let Some(user_agent) = &self.user_agent {
+            config.user_agent = user_agent.clone();
+        }
+        config
+    }
+
+    async fn within<T, E>(
+        &self,
+        future: impl Future<Output = std::result::Result<T, E>>,
+    ) -> Result<T>
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        match tokio::time::timeout(Duration::from_secs(self.timeout), future).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(format!("timed out after {}s", self.timeout).into()),
+        }
+    }
+
+    /// Connects to `target`, a host with an optional port, and handshakes.
+    async fn connect(&self, target: &str) -> Result<Connection> {
+        self.connect_with(target, &self.config()).await
+    }
+
+    async fn connect_with(&self, target: &str, config: &PeerConfig) -> Result<Connection> {
+        let (host, port) = split_host_port(target, self.network.default_port())?;
+        let (transport, addr): (Box<dyn Transport>, _) = match &self.proxy {
+            Some(proxy) => {
+                // Hostnames are left for the proxy to resolve.
+                let ip = host.parse().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+                let stream = self
+                    .within(Socks5Stream::connect(proxy.as_str(), (host.as_str(), port)))
+                    .await?;
+                (Box::new(stream), SocketAddr::new(ip, port))
+            }
+            None => {
+                let stream = self
+                    .within(TcpStream::connect((host.as_str(), port)))
+                    .await?;
+                let addr = stream.peer_addr()?;
+                (Box::new(stream), addr)
+            }
+        };
+        self.within(Peer::handshake(transport, addr, config)).await
+    }
 
-    let message = Message::new(
-        0xD9B4BEF9,
-        Command::Version,
-        Payload::Version(version_message),
+    async fn recv(&self, peer: &mut Connection) -> Result<Payload> {
+        self.within(peer.recv()).await
+    }
+}
+
+/// Splits `host:port`, `[ipv6]:port` or a bare host.
+fn split_host_port(target: &str, default_port: u16) -> Result<(String, u16)> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok((addr.ip().to_string(), addr.port()));
+    }
+    if let Ok(ip) = target
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+    {
+        return Ok((ip.to_string(), default_port));
+    }
+    match target.rsplit_once(':') {
+        Some((host, port)) => Ok((host.to_string(), port.parse()?)),
+        None => Ok((target.to_string(), default_port)),
+    }
+}
+
+fn version_json(addr: SocketAddr, version: &VersionMessage) -> serde_json::Value {
+    json!({
+        "addr": addr.to_string(),
+        "version": version.version,
+        "services": version.services.bits(),
+        "service_names": version.services.names(),
+        "user_agent": version.user_agent.as_str(),
+        "start_height": version.start_height,
+        "relay": version.relay,
+    })
+}
+
+fn print_version(addr: SocketAddr, version: &VersionMessage) {
+    println!("{}", addr);
+    println!("  version       {}", version.version);
+    println!(
+        "  services      {} ({})",
+        version.services.bits(),
+        version.services
     );
+    println!("  user agent    {}", version.user_agent);
+    println!("  start height  {}", version.start_height);
+    println!("  relay         {}", version.relay);
+}
 
-    println!("Sending version message");
-    sink.send(message).await?;
-    loop {
-        if let Some(response) = stream.next().await {
-            let message = match response {
-                Ok(message) => message,
-                Err(e) => {
-                    println!("Error: {}", e);
-                    continue;
-                }
-            };
+/// One line about a message, for monitoring.
+fn summary(payload: &Payload) -> String {
+    let command = payload.command().name();
+    match payload {
+        Payload::Inv(items) | Payload::GetData(items) | Payload::NotFound(items) => {
+            format!("{} {} items", command, items.len())
+        }
+        Payload::Tx(tx) => format!("{} {}", command, tx.txid()),
+        Payload::Block(block) => format!(
+            "{} {} with {} transactions",
+            command,
+            block.block_hash(),
+            block.transaction_count()
+        ),
+        Payload::Headers(headers) => format!("{} {}", command, headers.len()),
+        Payload::Addr(addresses) => format!("{} {} addresses", command, addresses.len()),
+        Payload::Ping(nonce) | Payload::Pong(nonce) => format!("{} {}", command, nonce),
+        Payload::FeeFilter(rate) => format!("{} {} sat/kvB", command, rate),
+        _ => command.to_string(),
+    }
+}
 
-            match message.payload() {
-                Payload::Version(version) => {
-                    println!("version message received: {:?}", version);
-                    let message = Message::new(0xD9B4BEF9, Command::VerAck, Payload::VerAck);
-                    println!("Sending verack: {:?}", message);
-                    sink.send(message).await?;
-                }
-                Payload::VerAck => {
-                    println!("verack message received");
-                }
-                Payload::SendHeaders => {
-                    println!("sendheaders received. Closing connection.");
-                    break;
+fn print_message(cli: &Cli, addr: SocketAddr, payload: &Payload) {
+    if cli.json {
+        println!(
+            "{}",
+            json!({
+                "addr": addr.to_string(),
+                "command": payload.command().name(),
+                "summary": summary(payload),
+            })
+        );
+    } else {
+        println!("{} {}", addr, summary(payload));
+    }
+}
+
+async fn handshake(cli: &Cli, target: &str) -> Result<()> {
+    let peer = cli.connect(target).await?;
+    if cli.json {
+        println!("{}", version_json(peer.addr(), peer.version()));
+    } else {
+        print_version(peer.addr(), peer.version());
+    }
+    Ok(())
+}
+
+async fn ping(cli: &Cli, target: &str, count: u32) -> Result<()> {
+    let mut peer = cli.connect(target).await?;
+    for _ in 0..count {
+        let nonce = rand::random();
+        let start = Instant::now();
+        peer.send(Payload::Ping(nonce)).await?;
+        while cli.recv(&mut peer).await? != Payload::Pong(nonce) {}
+        let millis = start.elapsed().as_secs_f64() * 1000.0;
+        if cli.json {
+            println!(
+                "{}",
+                json!({ "addr": peer.addr().to_string(), "ms": millis })
+            );
+        } else {
+            println!("pong from {} in {:.1} ms", peer.addr(), millis);
+        }
+    }
+    Ok(())
+}
+
+async fn getaddr(cli: &Cli, target: &str) -> Result<()> {
+    let mut peer = cli.connect(target).await?;
+    peer.send(Payload::GetAddr).await?;
+    // Nodes may announce themselves in a single address before answering.
+    let addresses = loop {
+        if let Payload::Addr(addresses) = cli.recv(&mut peer).await? {
+            if addresses.len() > 1 {
+                break addresses;
+            }
+        }
+    };
+    for address in addresses {
+        let addr = SocketAddr::new(address.ip, address.port.into());
+        if cli.json {
+            println!(
+                "{}",
+                json!({
+                    "addr": addr.to_string(),
+                    "services": address.services.bits(),
+                    "time": address.time,
+                })
+            );
+        } else {
+            println!(
+                "{} services {} seen {}",
+                addr,
+                address.services.bits(),
+                address.time
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn headers(cli: &Cli, target: &str) -> Result<()> {
+    let mut peer = cli.connect(target).await?;
+    let mut chain = HeaderChain::new(cli.network);
+    sync_headers(&mut peer, &mut chain, Duration::from_secs(cli.timeout)).await?;
+    if cli.json {
+        println!(
+            "{}",
+            json!({ "height": chain.height(), "tip": chain.tip().to_string() })
+        );
+    } else {
+        println!("height {} tip {}", chain.height(), chain.tip());
+    }
+    Ok(())
+}
+
+async fn crawl(
+    cli: &Cli,
+    seeds: &[String],
+    options: CrawlOptions,
+    output: Option<&Path>,
+) -> Result<()> {
+    let port = cli.network.default_port();
+    let seeds = match seeds {
+        [] => cli
+            .network
+            .dns_seeds()
+            .iter()
+            .map(|seed| seed.to_string())
+            .collect(),
+        seeds => seeds.to_vec(),
+    };
+    let mut nodes = Vec::new();
+    for seed in &seeds {
+        let (host, port) = split_host_port(seed, port)?;
+        let resolved = tokio::net::lookup_host((host.as_str(), port)).await;
+        match resolved {
+            Ok(addrs) => nodes.extend(addrs),
+            Err(e) => eprintln!("{}: {}", seed, e),
+        }
+    }
+
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout()),
+    };
+    if !cli.json {
+        writeln!(out, "{}", CSV_HEADER)?;
+    }
+    let connector = cli.clone();
+    let connect = move |addr: SocketAddr| {
+        let cli = connector.clone();
+        async move { cli.connect(&addr.to_string()).await }
+    };
+    let mut records = std::pin::pin!(handshake::bitcoin::crawl::crawl(nodes, options, connect));
+    let (mut visited, mut reachable) = (0, 0);
+    while let Some(record) = records.next().await {
+        visited += 1;
+        reachable += record.is_reachable() as usize;
+        if cli.json {
+            writeln!(out, "{}", record_json(&record))?;
+        } else {
+            writeln!(out, "{}", record_csv(&record))?;
+        }
+        if output.is_some() && visited % 100 == 0 {
+            eprintln!("{} nodes visited, {} reachable", visited, reachable);
+        }
+    }
+    out.flush()?;
+    eprintln!("{} nodes visited, {} reachable", visited, reachable);
+    Ok(())
+}
+
+const CSV_HEADER: &str =
+    "addr,version,services,user_agent,start_height,latency_ms,last_seen,addresses,error,implementation,implementation_version";
+
+/// The application part of a node's user agent, to group nodes by.
+fn implementation(record: &NodeRecord) -> Option<Component> {
+    let user_agent: UserAgent = record.version.as_ref()?.user_agent.as_str().parse().ok()?;
+    user_agent.implementation().cloned()
+}
+
+fn unix_time(time: Option<SystemTime>) -> Option<u64> {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs())
+}
+
+fn record_json(record: &NodeRecord) -> serde_json::Value {
+    let version = record.version.as_ref();
+    let implementation = implementation(record);
+    json!({
+        "addr": record.addr.to_string(),
+        "version": version.map(|version| version.version),
+        "services": version.map(|version| version.services.bits()),
+        "service_names": version.map(|version| version.services.names()),
+        "user_agent": version.map(|version| version.user_agent.as_str()),
+        "start_height": version.map(|version| version.start_height),
+        "latency_ms": record.latency.map(|latency| latency.as_millis() as u64),
+        "last_seen": unix_time(record.last_seen),
+        "addresses": record.addresses,
+        "error": record.error,
+        "implementation": implementation.as_ref().map(|component| &component.name),
+        "implementation_version": implementation.as_ref().map(|component| &component.version),
+    })
+}
+
+fn record_csv(record: &NodeRecord) -> String {
+    fn field(value: Option<impl ToString>) -> String {
+        let value = value.map(|value| value.to_string()).unwrap_or_default();
+        if value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value
+        }
+    }
+    let version = record.version.as_ref();
+    let implementation = implementation(record);
+    [
+        field(Some(record.addr)),
+        field(version.map(|version| version.version)),
+        field(version.map(|version| version.services.bits())),
+        field(version.map(|version| version.user_agent.as_str())),
+        field(version.map(|version| version.start_height)),
+        field(record.latency.map(|latency| latency.as_millis())),
+        field(unix_time(record.last_seen)),
+        field(Some(record.addresses)),
+        field(record.error.as_deref()),
+        field(implementation.as_ref().map(|component| &component.name)),
+        field(implementation.as_ref().map(|component| &component.version)),
+    ]
+    .join(",")
+}
+
+async fn listen(cli: &Cli, bind: Option<SocketAddr>, banlist: Option<&Path>) -> Result<()> {
+    let bind = bind.unwrap_or_else(|| {
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), cli.network.default_port())
+    });
+    let bans = match banlist {
+        Some(path) => BanList::open(path)?,
+        None => BanList::new(),
+    };
+    let bans = Arc::new(Mutex::new(BanManager::new(bans, BanOptions::default())));
+    let listener = TcpListener::bind(bind).await?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+    let config = cli.config();
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        if bans.lock().unwrap().is_banned(addr.ip()) {
+            eprintln!("{}: banned", addr);
+            continue;
+        }
+        let config = config.clone();
+        let cli = cli.clone();
+        let bans = bans.clone();
+        tokio::spawn(async move {
+            let transport: Box<dyn Transport> = Box::new(stream);
+            let handshake = Peer::handshake(transport, addr, &config);
+            let mut peer =
+                match tokio::time::timeout(Duration::from_secs(cli.timeout), handshake).await {
+                    Ok(Ok(peer)) => peer,
+                    Ok(Err(e)) => {
+                        misbehaved(&bans, addr, &e);
+                        return;
+                    }
+                    Err(_) => return eprintln!("{}: handshake timed out", addr),
+                };
+            if cli.json {
+                println!("{}", version_json(addr, peer.version()));
+            } else {
+                print_version(addr, peer.version());
+            }
+            let mut addrs = 0;
+            loop {
+                match peer.recv().await {
+                    Ok(payload) => {
+                        print_message(&cli, addr, &payload);
+                        if let Some(violation) = unsolicited(&payload, &mut addrs) {
+                            if misbehaved(&bans, addr, &violation.into()) {
+                                return;
+                            }
+                        }
+                    }
+                    // Skipped frames leave the connection usable.
+                    Err(e @ handshake::bitcoin::Error::Protocol(_)) => {
+                        if misbehaved(&bans, addr, &e) {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        misbehaved(&bans, addr, &e);
+                        return;
+                    }
                 }
             }
+        });
+    }
+}
+
+/// Addr messages a peer may send before they count as unsolicited.
+const ADDR_MESSAGES: usize = 3;
+
+/// The rule `payload` breaks on its way to a listener, which never asks for
+/// anything. `addrs` counts the addr messages seen so far.
+fn unsolicited(payload: &Payload, addrs: &mut usize) -> Option<Violation> {
+    match payload {
+        Payload::Block(_) | Payload::Tx(_) | Payload::Headers(_) => {
+            Some(Violation::Unsolicited(payload.command().name()))
+        }
+        Payload::Addr(_) => {
+            *addrs += 1;
+            (*addrs > ADDR_MESSAGES).then_some(Violation::Unsolicited("addr"))
+        }
+        _ => None,
+    }
+}
+
+/// Scores an error from the peer at `addr`. Returns whether it got banned.
+fn misbehaved(
+    bans: &Mutex<BanManager>,
+    addr: SocketAddr,
+    error: &handshake::bitcoin::Error,
+) -> bool {
+    eprintln!("{}: {}", addr, error);
+    match bans.lock().unwrap().report(addr.ip(), error) {
+        Ok(true) => {
+            eprintln!("{}: banned", addr);
+            true
+        }
+        Ok(false) => false,
+        Err(e) => {
+            eprintln!("{}: can't save ban: {}", addr, e);
+            true
+        }
+    }
+}
+
+/// The contents of a file if `input` names one, else `input` itself.
+fn read_input(input: &str) -> Result<Vec<u8>> {
+    match std::fs::read(input) {
+        Ok(contents) => Ok(contents),
+        Err(_) => Ok(input.as_bytes().to_vec()),
+    }
+}
+
+fn decode(cli: &Cli, input: &str) -> Result<()> {
+    let bytes = match std::fs::read(input) {
+        Ok(contents) => contents,
+        Err(_) => hex::decode(input.trim())?,
+    };
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let mut frame = decode_frame(cli, &bytes[offset..])
+            .map_err(|e| format!("frame at offset {}: {}", offset, e))?;
+        frame["offset"] = offset.into();
+        offset += HEADER_SIZE + frame["length"].as_u64().unwrap_or_default() as usize;
+        if cli.json {
+            println!("{}", frame);
         } else {
-            println!("Closed");
-            break;
+            print_frame(&frame);
         }
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
     }
+    Ok(())
+}
 
+/// The header fields of the frame `bytes` starts with, and its payload if
+/// the command is one we know. The checksum is reported, not enforced.
+fn decode_frame(cli: &Cli, bytes: &[u8]) -> Result<serde_json::Value> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(format!("{} bytes left, too short for a header", bytes.len()).into());
+    }
+    let magic = u32::from_le_bytes(bytes[0..4].try_into()?);
+    let command = &bytes[4..16];
+    let length = u32::from_le_bytes(bytes[16..20].try_into()?) as usize;
+    let checksum = u32::from_le_bytes(bytes[20..HEADER_SIZE].try_into()?);
+    let payload = bytes
+        .get(HEADER_SIZE..HEADER_SIZE + length)
+        .ok_or_else(|| format!("payload of {} bytes is truncated", length))?;
+    let name = String::from_utf8_lossy(command);
+    let mut frame = json!({
+        "magic": magic,
+        "network": Network::from_magic(magic).map(|network| network.to_string()),
+        "command": name.trim_end_matches('\0'),
+        "length": length,
+        "checksum": hex::encode(&bytes[20..HEADER_SIZE]),
+        "checksum_valid": payload.sha256() == checksum,
+    });
+    let context = Context {
+        network: Network::from_magic(magic).unwrap_or(cli.network),
+        ..Context::default()
+    };
+    let decoded = handshake::bitcoin::Command::decode(&mut &command[..]).and_then(|command| {
+        let mut remaining = payload;
+        let payload = Payload::decode_command(command, &mut remaining, &context)?;
+        if !remaining.is_empty() {
+            frame["trailing"] = remaining.len().into();
+        }
+        Ok(payload)
+    });
+    match decoded {
+        // The command and payload as serde has them, so the frame reads back
+        // as a `Message`.
+        Ok(payload) => {
+            if let serde_json::Value::Object(fields) = serde_json::to_value(&payload)? {
+                frame.as_object_mut().unwrap().extend(fields);
+            }
+        }
+        Err(e) => {
+            frame["payload_hex"] = hex::encode(payload).into();
+            frame["error"] = e.to_string().into();
+        }
+    }
+    Ok(frame)
+}
+
+fn print_frame(frame: &serde_json::Value) {
+    let checksum = match frame["checksum_valid"].as_bool() {
+        Some(true) => "valid",
+        _ => "INVALID",
+    };
+    let network = frame["network"].as_str().unwrap_or("unknown network");
+    let magic = frame["magic"].as_u64().unwrap_or_default() as u32;
+    println!("frame at offset {}", frame["offset"]);
+    println!(
+        "  magic     {} ({})",
+        hex::encode(magic.to_le_bytes()),
+        network
+    );
+    println!(
+        "  command   {}",
+        frame["command"].as_str().unwrap_or_default()
+    );
+    println!("  length    {}", frame["length"]);
+    println!(
+        "  checksum  {} ({})",
+        frame["checksum"].as_str().unwrap_or_default(),
+        checksum
+    );
+    if let Some(trailing) = frame.get("trailing") {
+        println!("  trailing  {} bytes after the payload", trailing);
+    }
+    if let Some(error) = frame["error"].as_str() {
+        println!("  error     {}", error);
+        println!(
+            "  payload   {}",
+            frame["payload_hex"].as_str().unwrap_or_default()
+        );
+    } else {
+        let payload = serde_json::to_string_pretty(&frame["payload"]).unwrap_or_default();
+        println!("  payload   {}", payload.replace('\n', "\n            "));
+    }
+}
+
+fn encode(cli: &Cli, input: &str, output: Option<&Path>) -> Result<()> {
+    let value: serde_json::Value = serde_json::from_slice(&read_input(input)?)?;
+    let messages = match value {
+        serde_json::Value::Array(messages) => messages,
+        message => vec![message],
+    };
+    let mut frames = Vec::new();
+    for (index, message) in messages.iter().enumerate() {
+        encode_message(cli, message, &mut frames)
+            .map_err(|e| format!("message {}: {}", index, e))?;
+    }
+    match output {
+        Some(path) => std::fs::write(path, frames)?,
+        None => println!("{}", hex::encode(frames)),
+    }
+    Ok(())
+}
+
+fn encode_message(cli: &Cli, message: &serde_json::Value, frames: &mut Vec<u8>) -> Result<()> {
+    let mut message = message.clone();
+    if let Some(fields) = message.as_object_mut() {
+        fields
+            .entry("magic")
+            .or_insert_with(|| cli.network.magic().into());
+    }
+    let message: Message = serde_json::from_value(message)?;
+    let context = Context {
+        network: cli.network,
+        ..Context::default()
+    };
+    message.encode_with(frames, &context)?;
     Ok(())
 }
+
+async fn monitor(cli: &Cli, target: &str) -> Result<()> {
+    let config = PeerConfig {
+        relay: true,
+        ..cli.config()
+    };
+    let mut peer = cli.connect_with(target, &config).await?;
+    loop {
+        let payload = peer.recv().await?;
+        print_message(cli, peer.addr(), &payload);
+    }
+}
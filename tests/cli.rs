@@ -0,0 +1,300 @@
+#![cfg(feature = "serde")]
+
+use handshake::bitcoin::capture::{peer_directory, read_peer, Direction};
+use handshake::bitcoin::mock::{MockConfig, MockNode};
+use handshake::bitcoin::peer::{Peer, PeerConfig};
+use handshake::bitcoin::{Address, Command, Encode, Message, Network, Payload, ServiceFlags};
+use std::net::SocketAddr;
+use std::process::Output;
+
+async fn run(args: &[&str]) -> Output {
+    tokio::process::Command::new(env!("CARGO_BIN_EXE_handshake"))
+        .args(args)
+        .output()
+        .await
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    let stdout = String::from_utf8(output.stdout.clone()).unwrap();
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    stdout
+}
+
+async fn mock(addresses: Vec<Address<u32>>) -> String {
+    let node = MockNode::new(MockConfig {
+        peer: PeerConfig {
+            network: Network::Regtest,
+            ..MockConfig::default().peer
+        },
+        addresses,
+        ..Default::default()
+    });
+    let addr = node.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    addr.to_string()
+}
+
+#[tokio::test]
+async fn handshake_and_ping() {
+    let addr = mock(vec![]).await;
+    let output = stdout(&run(&["handshake", &addr, "--network", "regtest"]).await);
+    assert!(output.contains("/mock:0.1.0/"), "{}", output);
+
+    let output = run(&[
+        "--network",
+        "regtest",
+        "--json",
+        "ping",
+        "--count",
+        "2",
+        &addr,
+    ])
+    .await;
+    let lines = stdout(&output)
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0]["ms"].is_number());
+}
+
+#[tokio::test]
+async fn capture() {
+    let addr = mock(vec![]).await;
+    let root = std::env::temp_dir().join(format!("cli-capture-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let capture = root.display().to_string();
+    let args = ["--network", "regtest", "--capture", &capture];
+    let args = [
+        &args[..],
+        &["--user-agent", "ourapp:1.2", "handshake", &addr],
+    ]
+    .concat();
+    stdout(&run(&args).await);
+
+    let records = read_peer(peer_directory(&root, addr.parse().unwrap())).unwrap();
+    let commands = records
+        .iter()
+        .filter(|record| record.direction == Direction::Inbound)
+        .map(|record| record.command.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(commands[..2], ["version", "verack"]);
+    let version = records
+        .iter()
+        .find(|record| record.direction == Direction::Outbound && record.command == "version")
+        .unwrap();
+    let user_agent = format!("/ourapp:1.2/handshake:{}/", env!("CARGO_PKG_VERSION"));
+    assert!(version
+        .payload
+        .windows(user_agent.len())
+        .any(|window| window == user_agent.as_bytes()));
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn rejects_invalid_user_agent() {
+    for app in ["ourapp", "our/app:1.2", ":1.2"] {
+        let output = run(&["--user-agent", app, "decode", "00"]).await;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("invalid value"), "{}: {}", app, stderr);
+    }
+}
+
+#[tokio::test]
+async fn getaddr() {
+    let seen: SocketAddr = "203.0.113.5:18444".parse().unwrap();
+    let address = Address {
+        time: 1_700_000_000,
+        services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+        ip: seen.ip(),
+        port: seen.port().into(),
+    };
+    let addr = mock(vec![address.clone(), address]).await;
+    let output = stdout(&run(&["getaddr", &addr, "--network", "regtest"]).await);
+    assert_eq!(output.lines().count(), 2);
+    assert!(
+        output.starts_with("203.0.113.5:18444 services 9"),
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn crawl() {
+    let address = |addr: &str| {
+        let addr: SocketAddr = addr.parse().unwrap();
+        Address {
+            time: 1_700_000_000,
+            services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+            ip: addr.ip(),
+            port: addr.port().into(),
+        }
+    };
+    let leaf = mock(vec![]).await;
+    // Port 1 on localhost is as good as unreachable.
+    let seed = mock(vec![address(&leaf), address("127.0.0.1:1")]).await;
+    let output = stdout(&run(&["--network", "regtest", "--timeout", "1", "crawl", &seed]).await);
+
+    let mut lines = output.lines();
+    assert_eq!(lines.next().unwrap().split(',').next(), Some("addr"));
+    let mut records = lines
+        .map(|line| line.split(',').map(String::from).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    records.sort();
+    assert_eq!(records.len(), 3, "{}", output);
+    let by_addr = |addr: &str| records.iter().find(|record| record[0] == addr).unwrap();
+    assert_eq!(by_addr(&seed)[3], "/mock:0.1.0/");
+    assert_eq!(by_addr(&seed)[7], "2");
+    assert_eq!(by_addr(&seed)[9..], ["mock", "0.1.0"]);
+    assert_eq!(by_addr(&leaf)[3], "/mock:0.1.0/");
+    let unreachable = by_addr("127.0.0.1:1");
+    assert_eq!(unreachable[3], "");
+    assert_eq!(unreachable[6], "1700000000");
+    assert!(!unreachable[8].is_empty());
+}
+
+/// Runs `listen` on a free port, returning the process, its stderr and the
+/// address it listens on.
+async fn listen(
+    args: &[&std::ffi::OsStr],
+) -> (
+    tokio::process::Child,
+    tokio::io::Lines<tokio::io::BufReader<tokio::process::ChildStderr>>,
+    SocketAddr,
+) {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    let mut listener = tokio::process::Command::new(env!("CARGO_BIN_EXE_handshake"))
+        .args(["--network", "regtest", "listen", "127.0.0.1:0"])
+        .args(args)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut stderr = BufReader::new(listener.stderr.take().unwrap()).lines();
+    let line = stderr.next_line().await.unwrap().unwrap();
+    let addr: SocketAddr = line.strip_prefix("Listening on ").unwrap().parse().unwrap();
+    (listener, stderr, addr)
+}
+
+async fn connect(addr: SocketAddr, config: PeerConfig) -> handshake::bitcoin::Result<Peer> {
+    let connecting = Peer::connect(addr, &config);
+    tokio::time::timeout(std::time::Duration::from_secs(5), connecting)
+        .await
+        .unwrap()
+}
+
+fn regtest() -> PeerConfig {
+    PeerConfig {
+        network: Network::Regtest,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn listen_bans_misbehaving_peers() {
+    let banlist = std::env::temp_dir().join(format!("cli-banlist-{}", std::process::id()));
+    let _ = std::fs::remove_file(&banlist);
+    let (_listener, mut stderr, addr) = listen(&["--banlist".as_ref(), banlist.as_ref()]).await;
+
+    // Frames for another network cost a peer all its points at once.
+    assert!(connect(addr, PeerConfig::default()).await.is_err());
+    while !stderr
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .ends_with(": banned")
+    {}
+    let contents = std::fs::read_to_string(&banlist).unwrap();
+    assert!(contents.starts_with("127.0.0.1/32 "), "{}", contents);
+
+    assert!(connect(addr, regtest()).await.is_err());
+    std::fs::remove_file(&banlist).unwrap();
+}
+
+#[tokio::test]
+async fn listen_bans_peers_sending_unsolicited_data() {
+    let (_listener, mut stderr, addr) = listen(&[]).await;
+    let mut peer = connect(addr, regtest()).await.unwrap();
+    // Headers nobody asked for cost 10 points each.
+    for _ in 0..10 {
+        peer.send(Payload::Headers(vec![])).await.unwrap();
+    }
+    let mut lines = vec![];
+    while !lines
+        .last()
+        .is_some_and(|line: &String| line.ends_with(": banned"))
+    {
+        lines.push(stderr.next_line().await.unwrap().unwrap());
+    }
+    let unsolicited = lines
+        .iter()
+        .filter(|line| line.ends_with(": unsolicited headers"))
+        .count();
+    assert_eq!(unsolicited, 10, "{:?}", lines);
+    assert!(connect(addr, regtest()).await.is_err());
+}
+
+#[tokio::test]
+async fn decode() {
+    let magic = Network::Mainnet.magic();
+    let mut frames = vec![];
+    for nonce in [1, 2] {
+        Message::new(magic, Command::Ping, Payload::Ping(nonce))
+            .unwrap()
+            .encode(&mut frames)
+            .unwrap();
+    }
+    // Corrupts the second checksum, which decode reports but doesn't enforce.
+    frames[32 + 20] ^= 1;
+    let path = std::env::temp_dir().join(format!("cli-decode-{}", std::process::id()));
+    std::fs::write(&path, &frames).unwrap();
+
+    for input in [hex::encode(&frames), path.display().to_string()] {
+        let output = stdout(&run(&["decode", "--json", &input]).await);
+        let lines = output
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["command"], "ping");
+        assert_eq!(lines[0]["network"], "mainnet");
+        assert_eq!(lines[0]["checksum_valid"], true);
+        assert_eq!(lines[1]["offset"], 32);
+        assert_eq!(lines[1]["checksum_valid"], false);
+        assert_eq!(lines[1]["payload"], 2);
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn encode_round_trip() {
+    let magic = Network::Regtest.magic();
+    let version = Message::new(
+        magic,
+        Command::Version,
+        Payload::Version(PeerConfig::default().version_message("192.0.2.1:18444".parse().unwrap())),
+    )
+    .unwrap();
+    let mut frame = vec![];
+    version.encode(&mut frame).unwrap();
+
+    let decoded = stdout(&run(&["decode", "--json", &hex::encode(&frame)]).await);
+    assert!(decoded.contains(r#""ip":"192.0.2.1""#), "{}", decoded);
+    let output = stdout(&run(&["--network", "regtest", "encode", decoded.trim()]).await);
+    assert_eq!(output.trim(), hex::encode(&frame));
+
+    // Both subcommands speak the library's serde form.
+    let message: Message = serde_json::from_str(decoded.trim()).unwrap();
+    assert_eq!(message, version);
+    let json = serde_json::to_string(&version).unwrap();
+    let output = stdout(&run(&["--network", "regtest", "encode", &json]).await);
+    assert_eq!(output.trim(), hex::encode(&frame));
+}
@@ -0,0 +1,135 @@
+use futures::StreamExt;
+use handshake::bitcoin::capture::{peer_directory, read_peer, Direction, Replay};
+use handshake::bitcoin::download::{download, DownloadOptions};
+use handshake::bitcoin::headers::{sync_headers, HeaderChain};
+use handshake::bitcoin::mock::{fixture_chain, Action, MockConfig, MockNode};
+use handshake::bitcoin::peer::{Peer, PeerConfig};
+use handshake::bitcoin::{
+    Command, Context, Error, Hash256, Inventory, InventoryKind, Network, Payload, Violation,
+};
+use pretty_assertions::assert_eq;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+fn addr() -> SocketAddr {
+    "192.0.2.1:18444".parse().unwrap()
+}
+
+fn client() -> PeerConfig {
+    PeerConfig {
+        network: Network::Regtest,
+        ..Default::default()
+    }
+}
+
+async fn connect(config: MockConfig) -> Peer<tokio::io::DuplexStream> {
+    let (ours, theirs) = tokio::io::duplex(1 << 20);
+    let node = MockNode::new(config);
+    tokio::spawn(async move { node.serve(theirs).await });
+    Peer::handshake(ours, addr(), &client()).await.unwrap()
+}
+
+#[tokio::test]
+async fn syncs_headers_and_blocks() {
+    let chain = fixture_chain(Network::Regtest, 20);
+    let mut peer = connect(MockConfig {
+        chain: chain.clone(),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(peer.version().user_agent, "/mock:0.1.0/".into());
+
+    let mut headers = HeaderChain::new(Network::Regtest);
+    sync_headers(&mut peer, &mut headers, Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(headers.height(), 20);
+    assert_eq!(headers.tip(), chain[19].block_hash());
+
+    let blocks = download(vec![peer], headers.range(1..21), DownloadOptions::default())
+        .map(|item| item.unwrap())
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(blocks.len(), 20);
+    for (height, block) in blocks {
+        block.check_merkle_root().unwrap();
+        assert_eq!(block.to_block().unwrap(), chain[height as usize - 1]);
+    }
+}
+
+#[tokio::test]
+async fn survives_misbehavior() {
+    let inv = Payload::Inv(vec![Inventory {
+        kind: InventoryKind::Tx,
+        hash: Hash256::hash(b"tx"),
+    }]);
+    let mut peer = connect(MockConfig {
+        script: vec![
+            Action::Raw(b"garbage".to_vec()),
+            Action::BadChecksum(Payload::Ping(1)),
+            Action::WrongMagic(Network::Mainnet.magic(), Payload::Ping(2)),
+            Action::Split(inv.clone(), 5),
+            Action::Expect(Command::Mempool),
+            Action::Disconnect,
+        ],
+        ..Default::default()
+    })
+    .await;
+
+    assert_eq!(peer.recv().await.unwrap(), Payload::SendHeaders);
+    // Each resync is blamed on the first rule broken, and the connection
+    // goes on.
+    assert!(matches!(
+        peer.recv().await,
+        Err(Error::Protocol(Violation::WrongMagic { .. }))
+    ));
+    assert!(matches!(
+        peer.recv().await,
+        Err(Error::Protocol(Violation::Checksum { .. }))
+    ));
+    assert_eq!(peer.recv().await.unwrap(), inv);
+    assert_eq!(peer.skipped(), 7 + 2 * (24 + 8));
+
+    peer.send(Payload::Mempool).await.unwrap();
+    assert!(matches!(peer.recv().await, Err(Error::ConnectionClosed)));
+}
+
+#[tokio::test]
+async fn captures_handshake() {
+    let root = std::env::temp_dir().join(format!("mock-capture-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let (ours, theirs) = tokio::io::duplex(1 << 20);
+    let node = MockNode::new(MockConfig::default());
+    tokio::spawn(async move { node.serve(theirs).await });
+    let config = PeerConfig {
+        capture: Some(root.clone()),
+        ..client()
+    };
+    let mut peer = Peer::handshake(ours, addr(), &config).await.unwrap();
+    assert_eq!(peer.recv().await.unwrap(), Payload::SendHeaders);
+
+    let records = read_peer(peer_directory(&root, addr())).unwrap();
+    // Timestamps only order records to the microsecond, so directions are
+    // compared separately.
+    let commands = |direction| {
+        records
+            .iter()
+            .filter(|record| record.direction == direction)
+            .map(|record| record.command.as_str())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(commands(Direction::Outbound), vec!["version", "verack"]);
+    assert_eq!(
+        commands(Direction::Inbound),
+        vec!["version", "verack", "sendheaders"]
+    );
+    let mut replayed = Replay::new(
+        &records,
+        Context {
+            network: Network::Regtest,
+            ..Context::default()
+        },
+    );
+    assert!(replayed.all(|(_, message)| matches!(message, Ok(Some(_)))));
+    std::fs::remove_dir_all(&root).unwrap();
+}